@@ -1,19 +1,28 @@
 //! Support for giffel archive files.
+//!
+//! An archive starts with a header holding the magic, the dimensions of the frames and the number
+//! of colors in their palettes, followed by the size of the metadata block in bytes. The metadata
+//! block comes next, and the rest of the archive is made up of frames, each one a palette followed
+//! by the pixels.
+//!
+//! Older revisions of the format are still readable. They have a different magic, and store the
+//! dimensions as `u16`s instead of `u32`s; the oldest one also has no metadata size.
 
-use std::{
-    io::{Read, Seek, SeekFrom, Write},
-    mem::size_of,
-};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 use crate::{error::Error, image::Image};
 
-pub const MAGIC: &[u8] = b"GIFFEL22";
-pub const HEADER_SIZE: usize = MAGIC.len() + size_of::<u16>() * 2 + size_of::<u8>();
+pub const MAGIC: &[u8] = b"GIFFEL24";
+
+/// The magic of archives with `u16` dimensions.
+const MAGIC_V23: &[u8] = b"GIFFEL23";
+/// The magic of archives with `u16` dimensions and without a metadata block.
+const MAGIC_V22: &[u8] = b"GIFFEL22";
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Dimensions {
-    pub width: u16,
-    pub height: u16,
+    pub width: u32,
+    pub height: u32,
     pub palette_color_count: u8,
 }
 
@@ -23,8 +32,8 @@ impl Dimensions {
             return Err(Error::PaletteIsEmpty);
         }
         Ok(Self {
-            width: u16::try_from(image.width).map_err(|_| Error::FramesTooBig)?,
-            height: u16::try_from(image.height).map_err(|_| Error::FramesTooBig)?,
+            width: u32::try_from(image.width).map_err(|_| Error::FramesTooBig)?,
+            height: u32::try_from(image.height).map_err(|_| Error::FramesTooBig)?,
             palette_color_count: u8::try_from(palette.len() - 1)
                 .map_err(|_| Error::PaletteTooBig)?,
         })
@@ -48,22 +57,69 @@ impl Dimensions {
 
     /// Returns the size (in bytes) of a single frame saved in a giffel archive with these
     /// dimensions.
-    fn frame_size(&self) -> usize {
+    pub fn frame_size(&self) -> usize {
         self.width() * self.height() + (self.palette_color_count()) * 3
     }
 }
 
+/// Information about an archive that isn't needed to read its frames.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// The filenames of the images each frame was made from, in order, without their
+    /// directories. Empty if the archive doesn't record them.
+    pub frame_names: Vec<String>,
+}
+
+impl Metadata {
+    fn write<W>(&self, mut writer: W) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        let count = u32::try_from(self.frame_names.len()).map_err(|_| Error::MetadataTooBig)?;
+        writer.write_all(&count.to_le_bytes())?;
+        for name in &self.frame_names {
+            let len = u16::try_from(name.len()).map_err(|_| Error::MetadataTooBig)?;
+            writer.write_all(&len.to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn read(mut block: &[u8]) -> Result<Self, Error> {
+        let mut take = |len: usize| {
+            if block.len() < len {
+                return Err(Error::InvalidMetadata);
+            }
+            let (taken, rest) = block.split_at(len);
+            block = rest;
+            Ok(taken)
+        };
+        let count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let frame_names = (0..count)
+            .map(|_| {
+                let len = u16::from_le_bytes(take(2)?.try_into().unwrap());
+                let name = take(usize::from(len))?.to_vec();
+                String::from_utf8(name).map_err(|_| Error::InvalidMetadata)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { frame_names })
+    }
+}
+
 /// Writer for giffel archive files.
 pub struct ArchiveWriter<W> {
     writer: W,
     dimensions: Option<Dimensions>,
+    metadata: Metadata,
 }
 
 impl<W> ArchiveWriter<W> {
-    pub fn new(writer: W) -> Self {
+    /// Creates a writer that stores the given metadata in the archive.
+    pub fn new(writer: W, metadata: Metadata) -> Self {
         Self {
             writer,
             dimensions: None,
+            metadata,
         }
     }
 }
@@ -72,24 +128,30 @@ impl<W> ArchiveWriter<W>
 where
     W: Write,
 {
-    fn write_dimensions(&mut self, dims: Dimensions) -> Result<(), Error> {
+    fn write_header(&mut self, dims: Dimensions) -> Result<(), Error> {
         self.writer.write_all(MAGIC)?;
         self.writer.write_all(&dims.width.to_le_bytes())?;
         self.writer.write_all(&dims.height.to_le_bytes())?;
         self.writer.write_all(&[dims.palette_color_count])?;
 
+        let mut metadata = vec![];
+        self.metadata.write(&mut metadata)?;
+        let metadata_size = u32::try_from(metadata.len()).map_err(|_| Error::MetadataTooBig)?;
+        self.writer.write_all(&metadata_size.to_le_bytes())?;
+        self.writer.write_all(&metadata)?;
+
         Ok(())
     }
 
     /// Writes frames to the archive. Each frame is made up of an image and a palette. Colors in the
     /// palette are specified in a slice of `[u8; 3]`, each array is an `[R, G, B]` color. The
-    /// color index 255 is treated as transparency.
+    /// last color in the palette is treated as transparency.
     ///
     /// Do note that every frame must have the same dimensions and palette color count.
     pub fn write_frame(&mut self, image: &Image<u8>, palette: &[[u8; 3]]) -> Result<(), Error> {
         if self.dimensions.is_none() {
             let dimensions = Dimensions::of(image, palette)?;
-            self.write_dimensions(dimensions)?;
+            self.write_header(dimensions)?;
             self.dimensions = Some(dimensions);
         }
         if Some(Dimensions::of(image, palette)?) != self.dimensions {
@@ -117,7 +179,12 @@ where
 pub struct ArchiveReader<R> {
     reader: R,
     pub dimensions: Dimensions,
+    pub metadata: Metadata,
     pub frame_count: usize,
+    /// The offset of the first frame, in bytes.
+    frames_offset: usize,
+    /// The total size of the archive, in bytes.
+    pub size: usize,
 }
 
 impl<R> ArchiveReader<R>
@@ -127,12 +194,21 @@ where
     /// Opens an archive for reading.
     pub fn new(mut reader: R) -> Result<Self, Error> {
         let magic = read_bytes::<_, { MAGIC.len() }>(&mut reader)?;
-        if magic != MAGIC {
+        if magic != MAGIC && magic != MAGIC_V23 && magic != MAGIC_V22 {
             return Err(Error::InvalidMagic);
         }
 
-        let width = u16::from_le_bytes(read_bytes(&mut reader)?);
-        let height = u16::from_le_bytes(read_bytes(&mut reader)?);
+        let (width, height) = if magic == MAGIC {
+            (
+                u32::from_le_bytes(read_bytes(&mut reader)?),
+                u32::from_le_bytes(read_bytes(&mut reader)?),
+            )
+        } else {
+            (
+                u16::from_le_bytes(read_bytes(&mut reader)?).into(),
+                u16::from_le_bytes(read_bytes(&mut reader)?).into(),
+            )
+        };
         let palette_color_count = read_bytes::<_, 1>(&mut reader)?[0];
         let dimensions = Dimensions {
             width,
@@ -140,16 +216,35 @@ where
             palette_color_count,
         };
 
+        let metadata = if magic == MAGIC_V22 {
+            Metadata::default()
+        } else {
+            let metadata_size = u32::from_le_bytes(read_bytes(&mut reader)?) as usize;
+            let mut metadata = vec![0; metadata_size];
+            reader.read_exact(&mut metadata)?;
+            Metadata::read(&metadata)?
+        };
+        let frames_offset = reader.stream_position()? as usize;
+
         let archive_size = reader.seek(SeekFrom::End(0))? as usize;
-        let frame_count = (archive_size - HEADER_SIZE) / dimensions.frame_size();
+        let frame_count = (archive_size - frames_offset) / dimensions.frame_size();
 
         Ok(Self {
             reader,
             dimensions,
+            metadata,
             frame_count,
+            frames_offset,
+            size: archive_size,
         })
     }
 
+    /// Returns the number of bytes at the end of the archive that do not make up a whole frame.
+    /// This is non-zero if the archive is truncated or corrupted.
+    pub fn trailing_bytes(&self) -> usize {
+        (self.size - self.frames_offset) % self.dimensions.frame_size()
+    }
+
     /// Read the frame at the specified index. Returns an error if there's no frame with the given
     /// index. Indices start at 1.
     pub fn read_frame(&mut self, index: usize) -> Result<(Image<u8>, Vec<[u8; 3]>), Error> {
@@ -160,7 +255,7 @@ where
             });
         }
         let index = index - 1;
-        let offset = HEADER_SIZE + index * self.dimensions.frame_size();
+        let offset = self.frames_offset + index * self.dimensions.frame_size();
         self.reader.seek(SeekFrom::Start(offset as u64))?;
 
         let mut palette = vec![0; self.dimensions.palette_color_count() * 3];
@@ -1,7 +1,5 @@
 //! Alpha cropping. Crops bitmaps to the non-alpha containing region to save space.
 
-use rayon::prelude::*;
-
 use crate::image::Image;
 
 #[derive(Debug, Clone, Copy)]
@@ -12,30 +10,47 @@ pub struct Rect {
     pub height: usize,
 }
 
-pub fn find_opaque_frame(image: &Image<u8>) -> Rect {
+impl Rect {
+    pub fn contains(&self, (x, y): (usize, usize)) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+
+    /// Returns the smallest rectangle containing both `self` and `other`.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// Finds the smallest rectangle containing all pixels of the image that are not `transparent`.
+///
+/// This is intentionally sequential, as it's meant to be run on many frames in parallel.
+pub fn find_opaque_frame(image: &Image<u8>, transparent: u8) -> Rect {
     let (left, right) = (0..image.height)
-        .into_par_iter()
         .map(|y| {
-            let left = (0..image.width).find(|&x| image[(x, y)] != 255);
-            let right = (0..image.width).rfind(|&x| image[(x, y)] != 255);
+            let left = (0..image.width).find(|&x| image[(x, y)] != transparent);
+            let right = (0..image.width).rfind(|&x| image[(x, y)] != transparent);
             (left.unwrap_or(image.width), right.unwrap_or(0))
         })
-        .reduce(
-            || (image.width, 0),
-            |(min_accum, max_accum), (min, max)| (min_accum.min(min), max_accum.max(max)),
-        );
+        .fold((image.width, 0), |(min_accum, max_accum), (min, max)| {
+            (min_accum.min(min), max_accum.max(max))
+        });
 
     let (top, bottom) = (0..image.width)
-        .into_par_iter()
         .map(|x| {
-            let top = (0..image.height).find(|&y| image[(x, y)] != 255);
-            let bottom = (0..image.height).rfind(|&y| image[(x, y)] != 255);
+            let top = (0..image.height).find(|&y| image[(x, y)] != transparent);
+            let bottom = (0..image.height).rfind(|&y| image[(x, y)] != transparent);
             (top.unwrap_or(image.height), bottom.unwrap_or(0))
         })
-        .reduce(
-            || (image.height, 0),
-            |(min_accum, max_accum), (min, max)| (min_accum.min(min), max_accum.max(max)),
-        );
+        .fold((image.height, 0), |(min_accum, max_accum), (min, max)| {
+            (min_accum.min(min), max_accum.max(max))
+        });
 
     Rect {
         x: left,
@@ -62,3 +77,22 @@ pub fn crop(image: &Image<u8>, rect: &Rect) -> Image<u8> {
 
     result
 }
+
+/// The inverse of [`crop`]: places a cropped image back onto a canvas of the given size, filling
+/// the rest of the canvas with `fill`.
+pub fn pad(image: &Image<u8>, rect: &Rect, width: usize, height: usize, fill: u8) -> Image<u8> {
+    let mut result = Image {
+        width,
+        height,
+        pixels: vec![fill; width * height],
+    };
+
+    for y in 0..rect.height {
+        let src_index = image.pixel_index((0, y));
+        let dst_index = result.pixel_index((rect.x, rect.y + y));
+        let scanline = &image.pixels[src_index..src_index + rect.width];
+        result.pixels[dst_index..dst_index + rect.width].copy_from_slice(scanline);
+    }
+
+    result
+}
@@ -70,7 +70,6 @@ pub fn dither(image: &Image<Oklab>, palette: &[Oklab], threshold: f32) -> Image<
         width: image.width,
         height: image.height,
         pixels: (0..pixel_count)
-            .into_iter()
             .map(|pixel_index| {
                 let x = pixel_index % image.width;
                 let y = pixel_index / image.width;
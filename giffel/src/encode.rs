@@ -0,0 +1,290 @@
+//! Encoders for stitched animations.
+
+use std::{borrow::Cow, io::Write, path::Path};
+
+use gif::{DisposalMethod, Extension, Repeat};
+
+use crate::{
+    crop::{pad, Rect},
+    error::Error,
+    image::Image,
+};
+
+/// The output format of a stitched animation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Gif,
+    Apng,
+    Webp,
+}
+
+impl Format {
+    /// Guesses the format from an output path's extension, falling back to GIF.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("apng" | "png") => Self::Apng,
+            Some("webp") => Self::Webp,
+            _ => Self::Gif,
+        }
+    }
+}
+
+/// A single cropped frame, ready to be encoded.
+pub struct Frame {
+    pub image: Image<u8>,
+    pub palette: Vec<[u8; 3]>,
+    /// The color index used for transparent pixels.
+    pub transparent: Option<u8>,
+    /// What happens to the frame once the next one is drawn.
+    pub dispose: DisposalMethod,
+    pub rect: Rect,
+}
+
+/// Settings shared by all encoders.
+pub struct Options {
+    pub width: u16,
+    pub height: u16,
+    pub frame_count: usize,
+    pub fps: u32,
+    pub repeat: Repeat,
+    /// Free-form text embedded in the output, if the format supports it.
+    pub comment: Option<String>,
+}
+
+/// Places a frame onto a transparent canvas of the given size.
+fn pad_frame(frame: &Frame, width: usize, height: usize) -> Image<u8> {
+    pad(
+        &frame.image,
+        &frame.rect,
+        width,
+        height,
+        frame.transparent.unwrap_or(0),
+    )
+}
+
+pub trait Encoder {
+    fn write_frame(&mut self, frame: &Frame) -> Result<(), Error>;
+
+    /// Finishes writing the animation. This must be called after all frames have been written.
+    fn finish(self: Box<Self>) -> Result<(), Error>;
+}
+
+/// Creates an encoder for the given format.
+pub fn encoder<'a>(
+    format: Format,
+    writer: Box<dyn Write + 'a>,
+    options: &Options,
+) -> Result<Box<dyn Encoder + 'a>, Error> {
+    Ok(match format {
+        Format::Gif => Box::new(GifEncoder::new(writer, options)?),
+        Format::Apng => Box::new(ApngEncoder::new(writer, options)?),
+        Format::Webp => Box::new(WebpEncoder::new(writer, options)),
+    })
+}
+
+pub struct GifEncoder<W: Write> {
+    encoder: gif::Encoder<W>,
+    delay: u16,
+}
+
+impl<W: Write> GifEncoder<W> {
+    pub fn new(writer: W, options: &Options) -> Result<Self, Error> {
+        let mut encoder = gif::Encoder::new(writer, options.width, options.height, &[])?;
+        encoder.set_repeat(options.repeat)?;
+        if let Some(comment) = &options.comment {
+            let comment = format!("{comment}\ngiffel {}", env!("CARGO_PKG_VERSION"));
+            encoder.write_raw_extension(Extension::Comment.into(), &[comment.as_bytes()])?;
+        }
+        let delay = gif_delay(options.fps)?;
+        Ok(Self { encoder, delay })
+    }
+}
+
+/// Returns the delay between frames (in milliseconds) that an animation with the given framerate
+/// is actually encoded with.
+pub fn frame_delay_ms(format: Format, fps: u32) -> Result<f64, Error> {
+    match format {
+        Format::Gif => Ok(f64::from(gif_delay(fps)?) * 10.0),
+        Format::Apng | Format::Webp if fps > 0 => Ok(1000.0 / f64::from(fps)),
+        Format::Apng | Format::Webp => Err(Error::InvalidFramerate(fps)),
+    }
+}
+
+/// Converts a framerate to a GIF frame delay, which is expressed in units of 10ms. Framerates
+/// whose delay isn't a whole multiple of 10ms are rounded to the nearest representable one.
+fn gif_delay(fps: u32) -> Result<u16, Error> {
+    if fps == 0 {
+        return Err(Error::InvalidFramerate(fps));
+    }
+    let exact = 100.0 / f64::from(fps);
+    // NOTE: A delay of 0 is interpreted by most decoders as "use some default", so the delay
+    // is kept at a minimum of 10ms.
+    let delay = exact.round().max(1.0);
+    if delay != exact {
+        eprintln!(
+            "warning: {fps} fps cannot be represented exactly in a GIF, it will play at {:.2} fps",
+            100.0 / delay
+        );
+    }
+    u16::try_from(delay as u64).map_err(|_| Error::InvalidFramerate(fps))
+}
+
+impl<W: Write> Encoder for GifEncoder<W> {
+    fn write_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        let Frame {
+            image,
+            palette,
+            transparent,
+            dispose,
+            rect,
+        } = frame;
+        let frame = gif::Frame {
+            delay: self.delay,
+            dispose: *dispose,
+            transparent: *transparent,
+            left: rect.x as u16,
+            top: rect.y as u16,
+            width: rect.width as u16,
+            height: rect.height as u16,
+            palette: Some(palette.iter().copied().flatten().collect()),
+            buffer: Cow::Borrowed(&image.pixels),
+            interlaced: false,
+            needs_user_input: false,
+        };
+        self.encoder.write_frame(&frame)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        self.encoder.into_inner()?;
+        Ok(())
+    }
+}
+
+pub struct ApngEncoder<W: Write> {
+    writer: png::Writer<W>,
+    width: usize,
+    height: usize,
+    is_first_frame: bool,
+}
+
+impl<W: Write> ApngEncoder<W> {
+    pub fn new(writer: W, options: &Options) -> Result<Self, Error> {
+        let mut encoder = png::Encoder::new(writer, options.width.into(), options.height.into());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        // NOTE: APNG counts the total number of plays, whereas GIF counts repetitions after the
+        // first play.
+        let plays = match options.repeat {
+            Repeat::Infinite => 0,
+            Repeat::Finite(repetitions) => u32::from(repetitions) + 1,
+        };
+        encoder.set_animated(options.frame_count as u32, plays)?;
+        let fps = u16::try_from(options.fps).map_err(|_| Error::InvalidFramerate(options.fps))?;
+        encoder.set_frame_delay(1, fps)?;
+        encoder.set_blend_op(png::BlendOp::Over)?;
+
+        Ok(Self {
+            writer: encoder.write_header()?,
+            width: options.width.into(),
+            height: options.height.into(),
+            is_first_frame: true,
+        })
+    }
+}
+
+impl<W: Write> Encoder for ApngEncoder<W> {
+    fn write_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        self.writer.set_dispose_op(match frame.dispose {
+            DisposalMethod::Background => png::DisposeOp::Background,
+            DisposalMethod::Previous => png::DisposeOp::Previous,
+            DisposalMethod::Any | DisposalMethod::Keep => png::DisposeOp::None,
+        })?;
+        // The first frame doubles as the default image, which must span the whole canvas.
+        let image = if self.is_first_frame {
+            self.is_first_frame = false;
+            Cow::Owned(pad_frame(frame, self.width, self.height))
+        } else {
+            self.writer.reset_frame_position()?;
+            self.writer
+                .set_frame_dimension(frame.rect.width as u32, frame.rect.height as u32)?;
+            self.writer
+                .set_frame_position(frame.rect.x as u32, frame.rect.y as u32)?;
+            Cow::Borrowed(&frame.image)
+        };
+        self.writer
+            .write_image_data(&image.to_rgba(&frame.palette, frame.transparent))?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+pub struct WebpEncoder<W: Write> {
+    writer: W,
+    width: usize,
+    height: usize,
+    fps: u32,
+    repeat: Repeat,
+    /// libwebp encodes all frames at once, so they're kept around as RGBA until the animation is
+    /// finished.
+    frames: Vec<Vec<u8>>,
+}
+
+impl<W: Write> WebpEncoder<W> {
+    pub fn new(writer: W, options: &Options) -> Self {
+        Self {
+            writer,
+            width: options.width.into(),
+            height: options.height.into(),
+            fps: options.fps,
+            repeat: options.repeat,
+            frames: Vec::with_capacity(options.frame_count),
+        }
+    }
+}
+
+impl<W: Write> Encoder for WebpEncoder<W> {
+    fn write_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        // libwebp finds the changed regions of each frame on its own, so it's fed whole frames.
+        let image = pad_frame(frame, self.width, self.height);
+        self.frames
+            .push(image.to_rgba(&frame.palette, frame.transparent));
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), Error> {
+        if self.fps == 0 {
+            return Err(Error::InvalidFramerate(self.fps));
+        }
+
+        let mut config =
+            webp::WebPConfig::new().map_err(|_| Error::WebpEncode("invalid config".into()))?;
+        config.lossless = 1;
+
+        let mut encoder = webp::AnimEncoder::new(self.width as u32, self.height as u32, &config);
+        // NOTE: Like APNG, WebP counts the total number of plays.
+        encoder.set_loop_count(match self.repeat {
+            Repeat::Infinite => 0,
+            Repeat::Finite(repetitions) => i32::from(repetitions) + 1,
+        });
+        for (i, rgba) in self.frames.iter().enumerate() {
+            let timestamp = (i as u64 * 1000 / u64::from(self.fps)) as i32;
+            encoder.add_frame(webp::AnimFrame::from_rgba(
+                rgba,
+                self.width as u32,
+                self.height as u32,
+                timestamp,
+            ));
+        }
+        let webp = encoder
+            .try_encode()
+            .map_err(|error| Error::WebpEncode(format!("{error:?}")))?;
+        self.writer.write_all(&webp)?;
+
+        Ok(())
+    }
+}
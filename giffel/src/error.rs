@@ -9,6 +9,10 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("GIF encoding error: {0}")]
     GifEncode(#[from] gif::EncodingError),
+    #[error("PNG encoding error: {0}")]
+    PngEncode(#[from] png::EncodingError),
+    #[error("WebP encoding error: {0}")]
+    WebpEncode(String),
 
     #[error("Palette must not be larger than 256 colors")]
     PaletteTooBig,
@@ -20,13 +24,38 @@ pub enum Error {
     FrameIncompatible,
     #[error("Frame index {got} is out of bounds ({count} frames are stored in the file)")]
     FrameOutOfBounds { got: usize, count: usize },
-    #[error("Frames are too big to encode in a GIF")]
+    #[error("Archive index {got} is out of bounds ({count} archives were provided)")]
+    ArchiveOutOfBounds { got: usize, count: usize },
+    #[error("Frames are too big to store in an archive")]
     FramesTooBig,
+    #[error(
+        "Animation is too big to encode ({width}x{height}, but it can be at most 65535 pixels \
+         wide and tall); use --width or --height to scale it down"
+    )]
+    AnimationTooBig { width: usize, height: usize },
     #[error("File does not appear to be a giffel archive")]
     InvalidMagic,
+    #[error("Archive metadata is corrupted")]
+    InvalidMetadata,
+    #[error("Archive metadata is too big (are the filenames very long?)")]
+    MetadataTooBig,
+    #[error("Archive records names for {names} frames, but has {frames} frames")]
+    FrameNameCountMismatch { names: usize, frames: usize },
+    #[error("Archive does not record the filenames of its frames")]
+    NoFrameNames,
+    #[error("Archive has {0} trailing bytes that do not make up a whole frame (is it truncated?)")]
+    TrailingBytes(usize),
+    #[error("Frame {frame} uses color index {index}, but its palette only has {count} colors")]
+    ColorIndexOutOfBounds {
+        frame: usize,
+        index: u8,
+        count: usize,
+    },
 
-    #[error("Invalid framerate supplied (frame delay exceeded 65536 - how?????)")]
-    InvalidFramerate,
+    #[error("Invalid framerate supplied ({0} fps cannot be encoded)")]
+    InvalidFramerate(u32),
     #[error("No frames provided")]
     EmptyGif,
+    #[error("Invalid frame selection: {0}")]
+    InvalidFrameSelection(String),
 }
@@ -15,6 +15,40 @@ impl<T> Image<T> {
     }
 }
 
+impl<T: Copy> Image<T> {
+    /// Resizes the image using nearest-neighbor sampling. Unlike other filters, this never
+    /// produces colors that weren't in the image already, so it's safe to use on indexed images.
+    pub fn resize_nearest(&self, width: usize, height: usize) -> Self {
+        let pixels = (0..height)
+            .flat_map(|y| {
+                let source_y = y * self.height / height;
+                (0..width).map(move |x| (x * self.width / width, source_y))
+            })
+            .map(|position| self[position])
+            .collect();
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+}
+
+impl Image<u8> {
+    /// Expands an indexed image into tightly packed RGBA8 pixels. Pixels using the `transparent`
+    /// color index are given an alpha of 0.
+    pub fn to_rgba(&self, palette: &[[u8; 3]], transparent: Option<u8>) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|&index| {
+                let [r, g, b] = palette[index as usize];
+                let a = if Some(index) == transparent { 0 } else { 255 };
+                [r, g, b, a]
+            })
+            .collect()
+    }
+}
+
 impl<T> Index<(usize, usize)> for Image<T> {
     type Output = T;
 
@@ -2,35 +2,42 @@ mod archive;
 mod colorspace;
 mod crop;
 mod dither;
+mod encode;
 mod error;
 mod image;
+mod optimize;
 mod palette;
+mod selection;
+mod ui;
 
 use std::{
-    borrow::Cow,
+    collections::BTreeSet,
     fs::File,
-    io::{Stderr, Write},
-    path::PathBuf,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
     sync::Arc,
-    time::Duration,
 };
 
+use ::image::{codecs::png::PngEncoder, ColorType, ImageEncoder};
 use clap::{Args, Parser, Subcommand};
-use gif::DisposalMethod;
+use gif::{DisposalMethod, Repeat};
 use parking_lot::Mutex;
-use pbr::ProgressBar;
 use rayon::prelude::*;
 
 use crate::{
-    crop::{crop, find_opaque_frame},
+    crop::{crop, find_opaque_frame, pad, Rect},
     image::Image,
+    ui::{status, Progress, ProgressMode},
 };
-use archive::{ArchiveReader, ArchiveWriter};
+use archive::{ArchiveReader, ArchiveWriter, Metadata};
 use colorspace::Oklab;
 use colorspace::Srgb;
 use dither::dither;
+use encode::{Format, Frame};
 use error::Error;
-use palette::extract_palette;
+use optimize::optimize;
+use palette::PaletteAlgorithm;
+use selection::FrameRange;
 
 /// A specialized GIF encoder whose main goal is being able to stitch selected frames
 /// into one GIF very fast.
@@ -39,44 +46,167 @@ use palette::extract_palette;
 struct Cli {
     #[clap(subcommand)]
     command: Command,
+    /// Don't print status messages or progress bars. Errors and warnings are still printed.
+    #[clap(short, long, global = true)]
+    quiet: bool,
+    /// When to draw progress bars.
+    #[clap(long, global = true, value_enum, default_value = "auto")]
+    progress: ProgressMode,
 }
 
 #[derive(Subcommand)]
 enum Command {
     /// Create a new giffel archive from the provided image files.
     Archive(ArchiveCommand),
-    /// Stitch frames from an archive into a GIF.
+    /// Combine several archives into one, with a single palette shared by all frames.
+    Merge(MergeCommand),
+    /// Redo palette extraction and dithering for an existing archive with different settings.
+    Repalette(RepaletteCommand),
+    /// Stitch frames from an archive into an animated GIF, APNG, or WebP.
     Stitch(StitchCommand),
     /// Return stats about an archive.
     Stat(StatCommand),
+    /// Extract a single frame from an archive into a PNG file.
+    Extract(ExtractCommand),
+    /// Extract all frames from an archive into a directory of PNG files.
+    Export(ExportCommand),
+    /// Check an archive for integrity problems, such as truncation or invalid color indices.
+    Verify(VerifyCommand),
 }
 
 #[derive(Args)]
 struct ArchiveCommand {
     /// The image files to pack into the archive.
     images: Vec<PathBuf>,
+    /// Read the image files from a list with one path per line instead of the command line.
+    /// Set to `-` for stdin.
+    #[clap(long, conflicts_with = "images")]
+    files_from: Option<String>,
     /// The output archive filename. Giffel archives usually use the extension `.giffel`.
     #[clap(short, long)]
     output: PathBuf,
     /// Disable sorting of filenames.
     #[clap(long)]
     no_sort: bool,
+    #[clap(flatten)]
+    quantize: QuantizeArgs,
 }
 
+/// Settings for reducing images to indexed color.
 #[derive(Args)]
-struct StitchCommand {
-    /// The archive to use.
+struct QuantizeArgs {
+    /// The number of colors in each frame's palette. Three of these are reserved for pure white,
+    /// pure black, and transparency.
+    #[clap(long, default_value = "256", value_parser = clap::value_parser!(u16).range(4..=256))]
+    colors: u16,
+    /// How strongly colors are dithered. Higher values result in more dithering, while 0 disables
+    /// dithering altogether.
+    #[clap(long, default_value = "0.05")]
+    dither_threshold: f32,
+    /// The number of refinement passes the k-means palette algorithm makes.
+    #[clap(long, default_value = "16")]
+    kmeans_iterations: usize,
+    /// The algorithm used to pick each frame's palette.
+    #[clap(long, value_enum, default_value = "kmeans")]
+    palette_algo: PaletteAlgorithm,
+}
+
+#[derive(Args)]
+struct MergeCommand {
+    /// The archives to merge, in order. Archives with differing dimensions are aligned to the top
+    /// left corner.
+    #[clap(required = true)]
+    archives: Vec<PathBuf>,
+    /// The output archive filename.
+    #[clap(short, long)]
+    output: PathBuf,
+    /// Settings used when the archives have more colors than fit in a single palette.
+    #[clap(flatten)]
+    quantize: QuantizeArgs,
+}
+
+#[derive(Args)]
+struct RepaletteCommand {
+    /// The archive to re-palettize.
     #[clap(short, long)]
     archive: PathBuf,
-    /// Which frames to use from the archive. Note that frame indices start at 1.
-    frames: Vec<usize>,
+    /// The output archive filename. This may be the same as the input archive.
+    #[clap(short, long)]
+    output: PathBuf,
+    #[clap(flatten)]
+    quantize: QuantizeArgs,
+}
+
+#[derive(Args)]
+struct StitchCommand {
+    /// The archives to use. This can be specified multiple times to stitch frames from more than
+    /// one archive; archives with differing dimensions are aligned to the top left corner of the
+    /// animation.
+    #[clap(short, long, required = true)]
+    archive: Vec<PathBuf>,
+    /// Which frames to use from the archives, in the form `[archive:]start[-end]`. Note that frame
+    /// indices start at 1, while archive indices start at 0 and default to the first archive.
+    /// Ranges are inclusive and are reversed if `start` is greater than `end`.
+    #[clap(value_parser)]
+    frames: Vec<FrameRange>,
+    /// Read the frame selection from a list with one range per line instead of the command line.
+    /// Set to `-` for stdin. Long selections can exceed the limit on the length of the command
+    /// line, but not this.
+    #[clap(long, conflicts_with = "frames")]
+    frames_from: Option<String>,
     /// Output path. Set to `-` for stdout.
     #[clap(short, long)]
     output: String,
-    /// The framerate to encode the GIF with. Note that not all values are valid; only framerates
-    /// coming from multiples of 10ms, greater than 20ms are supported (50 fps is the limit.)
+    /// The output format. If not specified, this is guessed from the output path's extension:
+    /// `.apng` and `.png` produce an APNG, `.webp` produces an animated WebP, and anything else
+    /// (including stdout) produces a GIF.
+    #[clap(short, long, value_enum)]
+    format: Option<Format>,
+    /// The framerate to encode the animation with. Note that GIFs only support frame delays that
+    /// are multiples of 10ms, so other framerates are rounded to the nearest supported one. Most
+    /// viewers don't play GIFs faster than 50 fps. APNGs and WebPs do not have this limitation.
     #[clap(short = 'r', long, default_value = "25")]
     fps: u32,
+    /// Print what would be encoded (frame indices, crop rectangles, frame delay, and output
+    /// dimensions) without writing any output.
+    #[clap(long)]
+    dry_run: bool,
+    /// How many times the GIF should loop. Set to `0` or `infinite` to loop endlessly.
+    #[clap(long, default_value = "infinite", value_parser = parse_repeat)]
+    repeat: Repeat,
+    /// Composite frames over a solid color, given in hexadecimal `RRGGBB` form, instead of leaving
+    /// transparent areas see-through.
+    #[clap(long, value_parser = parse_color)]
+    background: Option<[u8; 3]>,
+    /// Embed a comment in the GIF, alongside the version of giffel it was made with. Other formats
+    /// do not support comments.
+    #[clap(long)]
+    comment: Option<String>,
+    /// Only encode the parts of each frame that changed since the previous one. This can shrink
+    /// animations with mostly still frames considerably. Has no effect on WebPs, which are always
+    /// optimized this way.
+    #[clap(long)]
+    optimize: bool,
+    /// Scale the animation to the given width. If only one of `--width` and `--height` is given,
+    /// the other is chosen to preserve the aspect ratio. Frames are scaled with nearest-neighbor
+    /// sampling, so that no new colors are introduced.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    width: Option<u16>,
+    /// Scale the animation to the given height. See `--width`.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    height: Option<u16>,
+}
+
+fn parse_repeat(s: &str) -> Result<Repeat, String> {
+    match s {
+        "0" | "infinite" => Ok(Repeat::Infinite),
+        _ => s.parse().map(Repeat::Finite).map_err(|_| {
+            format!(
+                "repeat count must be `infinite` or a number between 0 and {}",
+                u16::MAX
+            )
+        }),
+    }
 }
 
 #[derive(Subcommand)]
@@ -87,6 +217,14 @@ enum StatTarget {
     Height,
     /// Get the number of images stored in the archive.
     FrameCount,
+    /// Get the filenames of the images the frames were made from, one per line prefixed with the
+    /// frame index. Only archives made with a version of `giffel archive` that records them have
+    /// any.
+    Sources {
+        /// Only print the filename of the frame with this index, without the prefix. Note that
+        /// frame indices start at 1.
+        frame: Option<usize>,
+    },
 }
 
 #[derive(Args)]
@@ -98,9 +236,34 @@ struct StatCommand {
     target: StatTarget,
 }
 
-fn progress_bar(max: u64) -> ProgressBar<Stderr> {
-    let stderr = std::io::stderr();
-    ProgressBar::on(stderr, max)
+#[derive(Args)]
+struct ExtractCommand {
+    /// The archive to extract the frame from.
+    #[clap(short, long)]
+    archive: PathBuf,
+    /// The index of the frame to extract. Note that frame indices start at 1.
+    #[clap(short, long)]
+    frame: usize,
+    /// Output path. Set to `-` for stdout.
+    #[clap(short, long)]
+    output: String,
+}
+
+#[derive(Args)]
+struct ExportCommand {
+    /// The archive to export frames from.
+    #[clap(short, long)]
+    archive: PathBuf,
+    /// The directory to write frames to. It is created if it doesn't exist. Frames are named
+    /// after their index, zero-padded so that they sort correctly, eg. `01.png`, `02.png`, etc.
+    #[clap(short, long)]
+    output_dir: PathBuf,
+}
+
+#[derive(Args)]
+struct VerifyCommand {
+    /// The archive to verify.
+    archive: PathBuf,
 }
 
 fn load_oklab_alpha_image(path: PathBuf) -> Result<(Image<Oklab>, Image<u8>), Error> {
@@ -127,13 +290,98 @@ fn load_oklab_alpha_image(path: PathBuf) -> Result<(Image<Oklab>, Image<u8>), Er
     Ok((oklab, alpha))
 }
 
+/// Reduces an image to indexed color, with a palette of its own. The last color of the palette is
+/// used for pixels that are transparent according to `alpha`.
+fn quantize(
+    oklab: &Image<Oklab>,
+    alpha: &Image<u8>,
+    args: &QuantizeArgs,
+) -> (Image<u8>, Vec<[u8; 3]>) {
+    let palette = extract_palette(oklab, args);
+    remap(oklab, alpha, palette, args.dither_threshold)
+}
+
+/// Extracts a palette for an image, leaving one free slot for the transparent color.
+fn extract_palette(oklab: &Image<Oklab>, args: &QuantizeArgs) -> Vec<Oklab> {
+    // NOTE: Leave three free slots for pure black, pure white, and transparency.
+    let color_count = usize::from(args.colors) - 3;
+    let mut palette = args
+        .palette_algo
+        .extractor(args.kmeans_iterations)
+        .extract(oklab, color_count);
+    // NOTE: All frames in an archive must have palettes of the same size, but not all algorithms
+    // are guaranteed to fill the whole palette if the image has few colors.
+    palette.resize(color_count, Oklab::BLACK);
+    palette.push(Oklab::WHITE);
+    palette.push(Oklab::BLACK);
+    palette
+}
+
+/// Dithers an image using the given palette. A transparent color is appended to the palette.
+fn remap(
+    oklab: &Image<Oklab>,
+    alpha: &Image<u8>,
+    mut palette: Vec<Oklab>,
+    dither_threshold: f32,
+) -> (Image<u8>, Vec<[u8; 3]>) {
+    let mut indexed = dither(oklab, &palette, dither_threshold);
+
+    let transparent = palette.len() as u8;
+    palette.push(Oklab::BLACK); // transparent
+
+    for y in 0..indexed.height {
+        for x in 0..indexed.width {
+            if alpha[(x, y)] < 128 {
+                indexed[(x, y)] = transparent;
+            }
+        }
+    }
+
+    let palette: Vec<_> = palette
+        .iter()
+        .map(|oklab| oklab.to_linear().to_srgb().to_array())
+        .collect();
+    (indexed, palette)
+}
+
+/// Reads the non-empty lines of a list, which is read from stdin if its path is `-`.
+fn read_list(list: &str) -> Result<Vec<String>, Error> {
+    let reader: Box<dyn BufRead> = if list == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(list)?))
+    };
+    let mut lines = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+fn read_file_list(list: &str) -> Result<Vec<PathBuf>, Error> {
+    Ok(read_list(list)?.into_iter().map(PathBuf::from).collect())
+}
+
+fn read_frame_list(list: &str) -> Result<Vec<FrameRange>, Error> {
+    read_list(list)?
+        .iter()
+        .map(|line| line.trim().parse().map_err(Error::InvalidFrameSelection))
+        .collect()
+}
+
 fn archive(command: ArchiveCommand) -> Result<(), Error> {
-    let mut images: Vec<_> = command
-        .images
+    let inputs = match &command.files_from {
+        Some(list) => read_file_list(list)?,
+        None => command.images,
+    };
+    let mut images: Vec<_> = inputs
         .into_iter()
         .flat_map(|path| {
             if path.is_dir() {
-                eprintln!("reading all files from input directory {path:?}");
+                status!("reading all files from input directory {path:?}");
                 let iter = match std::fs::read_dir(path) {
                     Ok(iter) => iter,
                     Err(error) => {
@@ -149,16 +397,19 @@ fn archive(command: ArchiveCommand) -> Result<(), Error> {
             }
         })
         .collect();
-    eprintln!("preparing images, this will take a while!");
+    status!("preparing images, this will take a while!");
     if !command.no_sort {
         images.sort_by(|a, b| {
             'try_parse_number: {
-                let (Some(a_stem), Some(b_stem)) = (a.file_stem(), b.file_stem())
-                    else { break 'try_parse_number };
-                let (Some(a_str), Some(b_str)) = (a_stem.to_str(), b_stem.to_str())
-                    else { break 'try_parse_number };
-                let (Ok(x), Ok(y)) = (a_str.parse::<usize>(), b_str.parse::<usize>())
-                    else { break 'try_parse_number };
+                let (Some(a_stem), Some(b_stem)) = (a.file_stem(), b.file_stem()) else {
+                    break 'try_parse_number;
+                };
+                let (Some(a_str), Some(b_str)) = (a_stem.to_str(), b_stem.to_str()) else {
+                    break 'try_parse_number;
+                };
+                let (Ok(x), Ok(y)) = (a_str.parse::<usize>(), b_str.parse::<usize>()) else {
+                    break 'try_parse_number;
+                };
                 return x.cmp(&y);
             }
             a.cmp(b)
@@ -166,49 +417,124 @@ fn archive(command: ArchiveCommand) -> Result<(), Error> {
     }
 
     let frame_count = images.len();
-    let progress = Arc::new(Mutex::new(progress_bar(frame_count as u64)));
-    progress
-        .lock()
-        .set_max_refresh_rate(Some(Duration::from_millis(20)));
+    let metadata = Metadata {
+        frame_names: images
+            .iter()
+            .map(|path| {
+                path.file_name()
+                    .map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+            })
+            .collect(),
+    };
+    let progress = Arc::new(Mutex::new(Progress::new(frame_count as u64)));
     let frames: Vec<_> = images
         .into_par_iter()
         .map({
             let progress = Arc::clone(&progress);
             move |path| {
                 let (oklab, alpha) = load_oklab_alpha_image(path).expect("cannot load image");
+                let (indexed, palette) = quantize(&oklab, &alpha, &command.quantize);
+                progress.lock().inc();
+                (indexed, palette)
+            }
+        })
+        .collect();
 
-                // NOTE: Generate 253 colors, leaving three free slots for pure black, pure white,
-                // and transparency.
-                let mut palette = extract_palette(&oklab, 253, 16);
-                palette.push(Oklab::WHITE);
-                palette.push(Oklab::BLACK);
+    status!("writing archive");
+    let mut progress = Progress::new(frame_count as u64);
+    let mut archive = ArchiveWriter::new(File::create(command.output)?, metadata);
+    for (image, palette) in frames {
+        archive.write_frame(&image, &palette)?;
+        progress.inc();
+    }
 
-                let mut indexed = dither(&oklab, &palette, 0.05);
+    Ok(())
+}
 
-                let transparent = palette.len() as u8;
-                palette.push(Oklab::BLACK); // transparent
+/// Returns the colors of an archived frame's palette that are used by any of its pixels. Only
+/// colors that are actually used count, and the transparent color is never used for anything else.
+fn used_colors(image: &Image<u8>, palette: &[[u8; 3]]) -> BTreeSet<[u8; 3]> {
+    let transparent = palette.len() - 1;
+    let mut used = [false; 256];
+    for &index in &image.pixels {
+        used[usize::from(index)] = true;
+    }
+    palette
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| used[index] && index != transparent)
+        .map(|(_, &color)| color)
+        .collect()
+}
 
-                for y in 0..indexed.height {
-                    for x in 0..indexed.width {
-                        if alpha[(x, y)] < 128 {
-                            indexed[(x, y)] = transparent;
-                        }
-                    }
+/// Reconstructs the colors of an archived frame. The transparent color is given an alpha of 0.
+fn unquantize(image: &Image<u8>, palette: &[[u8; 3]]) -> (Image<Oklab>, Image<u8>) {
+    let transparent = palette.len() - 1;
+    let palette: Vec<_> = palette
+        .iter()
+        .map(|&color| Srgb::from_array(color).to_linear().to_oklab())
+        .collect();
+    let oklab = Image {
+        width: image.width,
+        height: image.height,
+        pixels: image
+            .pixels
+            .iter()
+            .map(|&index| palette[usize::from(index)])
+            .collect(),
+    };
+    let alpha = Image {
+        width: image.width,
+        height: image.height,
+        pixels: image
+            .pixels
+            .iter()
+            .map(|&index| {
+                if usize::from(index) == transparent {
+                    0
+                } else {
+                    255
                 }
+            })
+            .collect(),
+    };
+    (oklab, alpha)
+}
 
-                let palette: Vec<_> = palette
-                    .iter()
-                    .map(|oklab| oklab.to_linear().to_srgb().to_array())
-                    .collect();
-                progress.lock().inc();
-                (indexed, palette)
-            }
+/// Re-quantizes an archive's frames. Since the original images are long gone, this starts from
+/// the already quantized frames, so each run loses a bit more detail; reducing the number of
+/// colors works well, but increasing it won't bring back any colors that were lost.
+fn repalette(command: RepaletteCommand) -> Result<(), Error> {
+    let mut reader = ArchiveReader::new(File::open(&command.archive)?)?;
+
+    status!("reading frames");
+    let mut progress = Progress::new(reader.frame_count as u64);
+    let frames = (1..=reader.frame_count)
+        .map(|index| {
+            let frame = reader.read_frame(index);
+            progress.inc();
+            frame
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // NOTE: All frames are read before the output is created, so that the input archive can be
+    // overwritten.
+    let metadata = reader.metadata;
+
+    status!("preparing frames, this will take a while!");
+    let progress = Arc::new(Mutex::new(Progress::new(frames.len() as u64)));
+    let frames: Vec<_> = frames
+        .into_par_iter()
+        .map(|(image, palette)| {
+            let (oklab, alpha) = unquantize(&image, &palette);
+            let frame = quantize(&oklab, &alpha, &command.quantize);
+            progress.lock().inc();
+            frame
         })
         .collect();
 
-    eprintln!("writing archive");
-    let mut progress = progress_bar(frame_count as u64);
-    let mut archive = ArchiveWriter::new(File::create(command.output)?);
+    status!("writing archive");
+    let mut progress = Progress::new(frames.len() as u64);
+    let mut archive = ArchiveWriter::new(File::create(command.output)?, metadata);
     for (image, palette) in frames {
         archive.write_frame(&image, &palette)?;
         progress.inc();
@@ -217,67 +543,346 @@ fn archive(command: ArchiveCommand) -> Result<(), Error> {
     Ok(())
 }
 
-fn stitch(command: StitchCommand) -> Result<(), Error> {
-    eprintln!("reading archive");
-    let mut archive = ArchiveReader::new(File::open(command.archive)?)?;
-    eprintln!("{:?}", archive.dimensions);
+fn merge(command: MergeCommand) -> Result<(), Error> {
+    status!("reading archives");
+    let mut frames = vec![];
+    // NOTE: Frame names are only kept if every archive has them, as otherwise they wouldn't line
+    // up with the frames anymore.
+    let mut frame_names = Some(vec![]);
+    let (mut width, mut height) = (0, 0);
+    for path in &command.archives {
+        let mut reader = ArchiveReader::new(File::open(path)?)?;
+        width = width.max(reader.dimensions.width());
+        height = height.max(reader.dimensions.height());
+        match &mut frame_names {
+            Some(names) if reader.metadata.frame_names.len() == reader.frame_count => {
+                names.append(&mut reader.metadata.frame_names)
+            }
+            _ => frame_names = None,
+        }
+        let mut progress = Progress::new(reader.frame_count as u64);
+        for index in 1..=reader.frame_count {
+            frames.push(reader.read_frame(index)?);
+            progress.inc();
+        }
+    }
+
+    // NOTE: Colors are deduplicated frame by frame, as collecting every pixel of every frame
+    // first would take as much memory as all the frames together.
+    let colors: BTreeSet<[u8; 3]> = frames
+        .par_iter()
+        .map(|(image, palette)| used_colors(image, palette))
+        .reduce(BTreeSet::new, |mut colors, frame_colors| {
+            colors.extend(frame_colors);
+            colors
+        });
+    let colors: Vec<_> = colors.into_iter().collect();
+
+    let frames: Vec<_> = if colors.len() < usize::from(command.quantize.colors) {
+        status!("all {} colors fit in a single palette", colors.len());
+        let transparent = colors.len() as u8;
+        let mut palette = colors.clone();
+        palette.push([0, 0, 0]); // transparent
+        frames
+            .into_par_iter()
+            .map(|(image, frame_palette)| {
+                let frame_transparent = frame_palette.len() - 1;
+                let remapped = frame_palette
+                    .iter()
+                    .enumerate()
+                    .map(|(index, color)| match index == frame_transparent {
+                        true => transparent,
+                        false => colors.binary_search(color).map_or(transparent, |i| i as u8),
+                    })
+                    .collect::<Vec<_>>();
+                let image = Image {
+                    width: image.width,
+                    height: image.height,
+                    pixels: image
+                        .pixels
+                        .iter()
+                        .map(|&index| remapped[usize::from(index)])
+                        .collect(),
+                };
+                (image, palette.clone())
+            })
+            .collect()
+    } else {
+        status!("reducing {} colors to a single palette", colors.len());
+        let all_colors = Image {
+            width: colors.len(),
+            height: 1,
+            pixels: colors
+                .iter()
+                .map(|&color| Srgb::from_array(color).to_linear().to_oklab())
+                .collect(),
+        };
+        let palette = extract_palette(&all_colors, &command.quantize);
+        let progress = Arc::new(Mutex::new(Progress::new(frames.len() as u64)));
+        frames
+            .into_par_iter()
+            .map(|(image, frame_palette)| {
+                let (oklab, alpha) = unquantize(&image, &frame_palette);
+                let frame = remap(
+                    &oklab,
+                    &alpha,
+                    palette.clone(),
+                    command.quantize.dither_threshold,
+                );
+                progress.lock().inc();
+                frame
+            })
+            .collect()
+    };
+
+    status!("writing archive");
+    let frame_count = frames.len();
+    let palette_size = frames.first().map_or(0, |(_, palette)| palette.len());
+    let mut progress = Progress::new(frame_count as u64);
+    let metadata = Metadata {
+        frame_names: frame_names.unwrap_or_default(),
+    };
+    let mut archive = ArchiveWriter::new(File::create(command.output)?, metadata);
+    for (image, palette) in frames {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: image.width,
+            height: image.height,
+        };
+        let transparent = (palette.len() - 1) as u8;
+        let image = pad(&image, &rect, width, height, transparent);
+        archive.write_frame(&image, &palette)?;
+        progress.inc();
+    }
+
+    println!("merged {frame_count} frames with a palette of {palette_size} colors");
 
-    let frame_count = command.frames.len();
+    Ok(())
+}
+
+fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let s = s.strip_prefix('#').unwrap_or(s);
+    let error = || format!("color must be in the hexadecimal form RRGGBB, got `{s}`");
+    if s.len() != 6 || !s.is_ascii() {
+        return Err(error());
+    }
+    let mut color = [0; 3];
+    for (i, channel) in color.iter_mut().enumerate() {
+        *channel = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| error())?;
+    }
+    Ok(color)
+}
+
+fn stitch(mut command: StitchCommand) -> Result<(), Error> {
+    let frame_ranges = match &command.frames_from {
+        Some(list) => read_frame_list(list)?,
+        None => std::mem::take(&mut command.frames),
+    };
+
+    status!("reading archives");
+    let mut archives = command
+        .archive
+        .iter()
+        .map(|path| ArchiveReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let original_width = archives.iter().map(|a| a.dimensions.width()).max().unwrap();
+    let original_height = archives
+        .iter()
+        .map(|a| a.dimensions.height())
+        .max()
+        .unwrap();
+    let (width, height) = scaled_size(
+        (original_width, original_height),
+        command.width,
+        command.height,
+    )?;
+    status!("{width}x{height}");
+    // NOTE: Frames from smaller archives are scaled by the same factor as the whole animation, so
+    // that they keep their size relative to the others.
+    let scale = |image: Image<u8>| {
+        if (usize::from(width), usize::from(height)) == (original_width, original_height) {
+            return image;
+        }
+        let scale_dimension = |dimension: usize, original: usize, scaled: u16| {
+            ((dimension * usize::from(scaled) + original / 2) / original).max(1)
+        };
+        let image_width = scale_dimension(image.width, original_width, width);
+        let image_height = scale_dimension(image.height, original_height, height);
+        image.resize_nearest(image_width, image_height)
+    };
+
+    let selected: Vec<_> = frame_ranges
+        .iter()
+        .flat_map(|range| range.indices().map(|index| (range.archive, index)))
+        .collect();
+    let frame_count = selected.len();
     if frame_count == 0 {
         return Err(Error::EmptyGif);
     }
 
-    let mut progress = progress_bar(frame_count as u64);
-    let frames: Vec<_> = command
-        .frames
+    let mut progress = Progress::new(frame_count as u64);
+    let frames = selected
         .iter()
-        .map(|&index| {
-            let (image, palette) = archive.read_frame(index).expect("cannot read frame");
+        .map(|&(archive, index)| {
+            let count = archives.len();
+            let frame = archives
+                .get_mut(archive)
+                .ok_or(Error::ArchiveOutOfBounds {
+                    got: archive,
+                    count,
+                })?
+                .read_frame(index);
             progress.inc();
-            (image, palette)
+            frame
         })
-        .map(|(image, palette)| {
-            let bounds = find_opaque_frame(&image);
-            let image = crop(&image, &bounds);
-            (image, palette, bounds)
+        .collect::<Result<Vec<_>, _>>()?;
+    // NOTE: Reading has to happen sequentially because the archive is a single file handle, but
+    // cropping is independent for each frame.
+    let frames: Vec<_> = frames
+        .into_par_iter()
+        .map(|(image, mut palette)| {
+            let image = scale(image);
+            let transparent = (palette.len() - 1) as u8;
+            if let Some(background) = command.background {
+                // Flattened frames are opaque, so they have to cover the whole canvas, including
+                // the area outside of smaller archives' frames.
+                let rect = Rect {
+                    x: 0,
+                    y: 0,
+                    width: image.width,
+                    height: image.height,
+                };
+                let (width, height) = (usize::from(width), usize::from(height));
+                let image = pad(&image, &rect, width, height, transparent);
+                palette[usize::from(transparent)] = background;
+                Frame {
+                    image,
+                    palette,
+                    transparent: None,
+                    // NOTE: Opaque frames cover the previous one entirely, so there's no need to
+                    // clear it.
+                    dispose: DisposalMethod::Keep,
+                    rect: Rect {
+                        x: 0,
+                        y: 0,
+                        width,
+                        height,
+                    },
+                }
+            } else {
+                let rect = find_opaque_frame(&image, transparent);
+                let image = crop(&image, &rect);
+                Frame {
+                    image,
+                    palette,
+                    transparent: Some(transparent),
+                    dispose: DisposalMethod::Background,
+                    rect,
+                }
+            }
         })
         .collect();
 
+    let format = command
+        .format
+        .unwrap_or_else(|| Format::from_path(Path::new(&command.output)));
+    // NOTE: libwebp is fed whole frames and finds the changed regions on its own.
+    let frames = if command.optimize && format != Format::Webp {
+        status!("optimizing frames");
+        optimize(&frames)
+    } else {
+        frames
+    };
+    if command.dry_run {
+        let labels: Vec<_> = selected
+            .iter()
+            .map(|&(archive, index)| match archives.len() {
+                1 => index.to_string(),
+                _ => format!("{archive}:{index}"),
+            })
+            .collect();
+        return print_plan(format, command.fps, (width, height), &labels, &frames);
+    }
+
     let writer: Box<dyn Write> = if command.output == "-" {
         Box::new(std::io::stdout())
     } else {
         Box::new(File::create(command.output)?)
     };
 
-    eprintln!("encoding frames");
-    let mut progress = progress_bar(frames.len() as u64);
-    let mut encoder = gif::Encoder::new(
-        writer,
-        archive.dimensions.width,
-        archive.dimensions.height,
-        &[],
-    )?;
-    encoder.set_repeat(gif::Repeat::Infinite)?;
-    let delay = u16::try_from(100 / command.fps).map_err(|_| Error::InvalidFramerate)?;
-    for (image, palette, rect) in frames {
-        let frame = gif::Frame {
-            delay,
-            dispose: DisposalMethod::Background,
-            transparent: Some(255),
-            left: rect.x as u16,
-            top: rect.y as u16,
-            width: rect.width as u16,
-            height: rect.height as u16,
-            palette: Some(palette.iter().copied().flatten().collect()),
-            buffer: Cow::Borrowed(&image.pixels),
-            interlaced: false,
-            needs_user_input: false,
-        };
-        encoder.write_frame(&frame)?;
+    status!("encoding frames");
+    let mut progress = Progress::new(frames.len() as u64);
+    let options = encode::Options {
+        width,
+        height,
+        frame_count: frames.len(),
+        fps: command.fps,
+        repeat: command.repeat,
+        comment: command.comment,
+    };
+    if options.comment.is_some() && format != Format::Gif {
+        eprintln!("warning: comments are only supported in GIFs, --comment will be ignored");
+    }
+    let mut encoder = encode::encoder(format, writer, &options)?;
+    for frame in &frames {
+        encoder.write_frame(frame)?;
         progress.inc();
     }
-    eprintln!("writing trailer");
-    let _writer = encoder.into_inner();
+    status!("writing trailer");
+    encoder.finish()?;
+
+    Ok(())
+}
+
+/// Returns the size of the animation after scaling it to the given width and height. A missing
+/// dimension is derived from the other one, preserving the aspect ratio. Archives can hold frames
+/// bigger than any of the output formats support, so this fails if the animation isn't scaled
+/// down to fit.
+fn scaled_size(
+    (width, height): (usize, usize),
+    to_width: Option<u16>,
+    to_height: Option<u16>,
+) -> Result<(u16, u16), Error> {
+    let derive = |scaled: u16, original: usize, other: usize| {
+        let derived = (other * usize::from(scaled) + original / 2) / original;
+        derived.clamp(1, usize::from(u16::MAX)) as u16
+    };
+    Ok(match (to_width, to_height) {
+        (Some(to_width), Some(to_height)) => (to_width, to_height),
+        (Some(to_width), None) => (to_width, derive(to_width, width, height)),
+        (None, Some(to_height)) => (derive(to_height, height, width), to_height),
+        (None, None) => match (u16::try_from(width), u16::try_from(height)) {
+            (Ok(width), Ok(height)) => (width, height),
+            _ => return Err(Error::AnimationTooBig { width, height }),
+        },
+    })
+}
+
+fn print_plan(
+    format: Format,
+    fps: u32,
+    (width, height): (u16, u16),
+    labels: &[String],
+    frames: &[Frame],
+) -> Result<(), Error> {
+    println!("format: {format:?}");
+    println!("dimensions: {width}x{height}");
+    println!("frame delay: {}ms", encode::frame_delay_ms(format, fps)?);
+    println!("frames: {}", frames.len());
+    for (label, frame) in labels.iter().zip(frames) {
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = frame.rect;
+        let dispose = match frame.dispose {
+            DisposalMethod::Keep => ", kept",
+            _ => "",
+        };
+        println!("  {label}: {width}x{height} at ({x}, {y}){dispose}");
+    }
 
     Ok(())
 }
@@ -290,18 +895,132 @@ fn stat(command: StatCommand) -> Result<(), Error> {
         StatTarget::Width => println!("{}", reader.dimensions.width),
         StatTarget::Height => println!("{}", reader.dimensions.height),
         StatTarget::FrameCount => println!("{}", reader.frame_count),
+        StatTarget::Sources { frame } => {
+            let names = &reader.metadata.frame_names;
+            if names.is_empty() {
+                return Err(Error::NoFrameNames);
+            }
+            match frame {
+                Some(index) => {
+                    let name = index.checked_sub(1).and_then(|i| names.get(i)).ok_or(
+                        Error::FrameOutOfBounds {
+                            got: index,
+                            count: names.len(),
+                        },
+                    )?;
+                    println!("{name}");
+                }
+                None => {
+                    for (i, name) in names.iter().enumerate() {
+                        println!("{}: {name}", i + 1);
+                    }
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
+/// Writes an indexed image out as an RGBA PNG.
+fn write_png<W>(writer: W, image: &Image<u8>, palette: &[[u8; 3]]) -> Result<(), Error>
+where
+    W: Write,
+{
+    PngEncoder::new(writer).write_image(
+        &image.to_rgba(palette, Some((palette.len() - 1) as u8)),
+        image.width as u32,
+        image.height as u32,
+        ColorType::Rgba8,
+    )?;
+    Ok(())
+}
+
+fn extract(command: ExtractCommand) -> Result<(), Error> {
+    let mut reader = ArchiveReader::new(File::open(&command.archive)?)?;
+    let (image, palette) = reader.read_frame(command.frame)?;
+
+    if command.output == "-" {
+        write_png(std::io::stdout().lock(), &image, &palette)
+    } else {
+        write_png(File::create(&command.output)?, &image, &palette)
+    }
+}
+
+fn export(command: ExportCommand) -> Result<(), Error> {
+    let mut reader = ArchiveReader::new(File::open(&command.archive)?)?;
+    std::fs::create_dir_all(&command.output_dir)?;
+
+    status!("reading frames");
+    let mut progress = Progress::new(reader.frame_count as u64);
+    let frames = (1..=reader.frame_count)
+        .map(|index| {
+            let frame = reader.read_frame(index);
+            progress.inc();
+            frame
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    status!("writing frames");
+    let digits = reader.frame_count.to_string().len();
+    let progress = Arc::new(Mutex::new(Progress::new(reader.frame_count as u64)));
+    frames
+        .into_par_iter()
+        .enumerate()
+        .try_for_each(|(i, (image, palette))| {
+            let path = command.output_dir.join(format!("{:0digits$}.png", i + 1));
+            write_png(File::create(path)?, &image, &palette)?;
+            progress.lock().inc();
+            Ok(())
+        })
+}
+
+fn verify(command: VerifyCommand) -> Result<(), Error> {
+    let mut reader = ArchiveReader::new(File::open(&command.archive)?)?;
+
+    let trailing_bytes = reader.trailing_bytes();
+    if trailing_bytes != 0 {
+        return Err(Error::TrailingBytes(trailing_bytes));
+    }
+    let names = reader.metadata.frame_names.len();
+    if names != 0 && names != reader.frame_count {
+        return Err(Error::FrameNameCountMismatch {
+            names,
+            frames: reader.frame_count,
+        });
+    }
+
+    let mut progress = Progress::new(reader.frame_count as u64);
+    for index in 1..=reader.frame_count {
+        let (image, palette) = reader.read_frame(index)?;
+        if let Some(&color) = image.pixels.iter().find(|&&i| i as usize >= palette.len()) {
+            return Err(Error::ColorIndexOutOfBounds {
+                frame: index,
+                index: color,
+                count: palette.len(),
+            });
+        }
+        progress.inc();
+    }
+
+    println!("ok: {} frames, {} bytes", reader.frame_count, reader.size);
+
+    Ok(())
+}
+
 fn main() -> Result<(), Error> {
     let args = Cli::parse();
+    ui::init(args.quiet, args.progress);
 
     match args.command {
         Command::Archive(cmd) => archive(cmd)?,
+        Command::Merge(cmd) => merge(cmd)?,
+        Command::Repalette(cmd) => repalette(cmd)?,
         Command::Stitch(cmd) => stitch(cmd)?,
         Command::Stat(cmd) => stat(cmd)?,
+        Command::Extract(cmd) => extract(cmd)?,
+        Command::Export(cmd) => export(cmd)?,
+        Command::Verify(cmd) => verify(cmd)?,
     }
 
     Ok(())
@@ -0,0 +1,142 @@
+//! Delta frame optimization. Instead of redrawing every frame from scratch, only the region that
+//! changed since the previous frame is encoded, and the previous frame is kept around underneath.
+
+use gif::DisposalMethod;
+use rayon::prelude::*;
+
+use crate::{crop::Rect, encode::Frame, image::Image};
+
+/// Returns the color of a frame at the given canvas position, or `None` if the pixel is
+/// transparent.
+fn color_at(frame: &Frame, position: (usize, usize)) -> Option<[u8; 3]> {
+    let index = index_at(frame, position)?;
+    (Some(index) != frame.transparent).then(|| frame.palette[usize::from(index)])
+}
+
+/// Returns the color index of a frame at the given canvas position, or `None` if the position is
+/// outside of the frame's rectangle.
+fn index_at(frame: &Frame, (x, y): (usize, usize)) -> Option<u8> {
+    let Rect {
+        x: left, y: top, ..
+    } = frame.rect;
+    frame
+        .rect
+        .contains((x, y))
+        .then(|| frame.image[(x - left, y - top)])
+}
+
+/// Finds the smallest rectangle containing all pixels within `area` for which `predicate` is
+/// true.
+fn bounding_box(area: &Rect, predicate: impl Fn((usize, usize)) -> bool) -> Option<Rect> {
+    let mut bounds: Option<Rect> = None;
+    for y in area.y..area.y + area.height {
+        for x in area.x..area.x + area.width {
+            if predicate((x, y)) {
+                let pixel = Rect {
+                    x,
+                    y,
+                    width: 1,
+                    height: 1,
+                };
+                bounds = Some(match bounds {
+                    Some(bounds) => bounds.union(&pixel),
+                    None => pixel,
+                });
+            }
+        }
+    }
+    bounds
+}
+
+/// Rewrites cropped frames such that each one only covers the region that differs from the frame
+/// before it.
+///
+/// A frame can only be drawn on top of the previous one if no pixel goes from opaque to
+/// transparent between them, since a transparent pixel leaves whatever was underneath it.
+/// Otherwise the previous frame is cleared after being displayed, which is what unoptimized
+/// frames always do.
+pub fn optimize(frames: &[Frame]) -> Vec<Frame> {
+    let disposals: Vec<_> = (0..frames.len())
+        .into_par_iter()
+        .map(|i| {
+            let frame = &frames[i];
+            let Some(next) = frames.get(i + 1) else {
+                // NOTE: The last frame has to clear up after itself so that the animation can
+                // start over from a blank canvas when it loops.
+                return match frame.transparent {
+                    Some(_) => DisposalMethod::Background,
+                    None => DisposalMethod::Keep,
+                };
+            };
+            let clears = bounding_box(&frame.rect, |position| {
+                color_at(frame, position).is_some() && color_at(next, position).is_none()
+            });
+            match clears {
+                Some(_) => DisposalMethod::Background,
+                None => DisposalMethod::Keep,
+            }
+        })
+        .collect();
+
+    (0..frames.len())
+        .into_par_iter()
+        .map(|i| {
+            let frame = &frames[i];
+            let previous = match i.checked_sub(1) {
+                Some(p) if disposals[p] == DisposalMethod::Keep => Some(&frames[p]),
+                _ => None,
+            };
+            let previous_color =
+                |position| previous.and_then(|previous| color_at(previous, position));
+
+            let area = match previous {
+                Some(previous) => frame.rect.union(&previous.rect),
+                None => frame.rect,
+            };
+            let changed = bounding_box(&area, |position| {
+                color_at(frame, position) != previous_color(position)
+            });
+            // NOTE: Clearing a frame only clears its own rectangle, so a frame that's going to be
+            // cleared must cover all of its opaque pixels.
+            let rect = match (changed, disposals[i]) {
+                (Some(changed), DisposalMethod::Background) => changed.union(&frame.rect),
+                (None, DisposalMethod::Background) => frame.rect,
+                (Some(changed), _) => changed,
+                // GIF frames can't be empty, so a frame identical to the previous one is reduced
+                // to a single pixel.
+                (None, _) => Rect {
+                    x: frame.rect.x,
+                    y: frame.rect.y,
+                    width: 1,
+                    height: 1,
+                },
+            };
+
+            let mut image = Image {
+                width: rect.width,
+                height: rect.height,
+                pixels: Vec::with_capacity(rect.width * rect.height),
+            };
+            for y in rect.y..rect.y + rect.height {
+                for x in rect.x..rect.x + rect.width {
+                    let unchanged = color_at(frame, (x, y)) == previous_color((x, y));
+                    let index = match frame.transparent {
+                        Some(transparent) if unchanged => transparent,
+                        _ => index_at(frame, (x, y))
+                            .or(frame.transparent)
+                            .expect("frames without transparency must cover the whole canvas"),
+                    };
+                    image.pixels.push(index);
+                }
+            }
+
+            Frame {
+                image,
+                palette: frame.palette.clone(),
+                transparent: frame.transparent,
+                dispose: disposals[i],
+                rect,
+            }
+        })
+        .collect()
+}
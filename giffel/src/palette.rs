@@ -27,6 +27,46 @@ fn find_closest_mean(observation: [f32; 3], means: &[Mean]) -> usize {
     min_index
 }
 
+/// An algorithm for picking a palette for an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PaletteAlgorithm {
+    /// k-means clustering in the Oklab color space. Slow, but produces good results.
+    Kmeans,
+    /// Median cut in the Oklab color space. Fast, but not as accurate as k-means.
+    Mediancut,
+    /// Octree color quantization. Fast, and tends to preserve small details.
+    Octree,
+}
+
+impl PaletteAlgorithm {
+    /// Creates the extractor implementing this algorithm. `kmeans_iterations` is only used by
+    /// k-means.
+    pub fn extractor(self, kmeans_iterations: usize) -> Box<dyn PaletteExtractor> {
+        match self {
+            Self::Kmeans => Box::new(KMeans {
+                iterations: kmeans_iterations,
+            }),
+            Self::Mediancut => Box::new(MedianCut),
+            Self::Octree => Box::new(Octree),
+        }
+    }
+}
+
+pub trait PaletteExtractor {
+    /// Picks at most `colors` colors that represent the image well.
+    fn extract(&self, image: &Image<Oklab>, colors: usize) -> Vec<Oklab>;
+}
+
+pub struct KMeans {
+    pub iterations: usize,
+}
+
+impl PaletteExtractor for KMeans {
+    fn extract(&self, image: &Image<Oklab>, colors: usize) -> Vec<Oklab> {
+        extract_palette(image, colors, self.iterations)
+    }
+}
+
 pub fn extract_palette(image: &Image<Oklab>, colors: usize, iterations: usize) -> Vec<Oklab> {
     let observations = {
         let mut observations: Vec<_> = image
@@ -80,3 +120,173 @@ pub fn extract_palette(image: &Image<Oklab>, colors: usize, iterations: usize) -
         )
         .collect()
 }
+
+/// A box of colors used by the median cut algorithm, along with the axis it's widest along.
+struct ColorBox {
+    colors: Vec<[f32; 3]>,
+    axis: usize,
+    extent: f32,
+}
+
+impl ColorBox {
+    fn new(colors: Vec<[f32; 3]>) -> Self {
+        let (axis, extent) = (0..3)
+            .map(|axis| {
+                let (min, max) = colors
+                    .iter()
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), color| {
+                        (min.min(color[axis]), max.max(color[axis]))
+                    });
+                (axis, max - min)
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        Self {
+            colors,
+            axis,
+            extent,
+        }
+    }
+
+    fn mean(&self) -> Oklab {
+        let [l, a, b] = self
+            .colors
+            .iter()
+            .fold([0.0; 3], |[a, b, c], [x, y, z]| [a + x, b + y, c + z])
+            .map(|x| x / self.colors.len() as f32);
+        Oklab { l, a, b }
+    }
+}
+
+pub struct MedianCut;
+
+impl PaletteExtractor for MedianCut {
+    fn extract(&self, image: &Image<Oklab>, colors: usize) -> Vec<Oklab> {
+        let pixels = image
+            .pixels
+            .iter()
+            .map(|color| [color.l, color.a, color.b])
+            .collect();
+        let mut boxes = vec![ColorBox::new(pixels)];
+
+        while boxes.len() < colors {
+            // A box with zero extent contains only a single color, so there's nothing to split.
+            let Some((index, _)) = boxes
+                .iter()
+                .enumerate()
+                .filter(|(_, color_box)| color_box.extent > 0.0)
+                .max_by(|(_, a), (_, b)| a.extent.total_cmp(&b.extent))
+            else {
+                break;
+            };
+
+            let ColorBox {
+                mut colors, axis, ..
+            } = boxes.swap_remove(index);
+            colors.sort_unstable_by(|a, b| a[axis].total_cmp(&b[axis]));
+            let upper = colors.split_off(colors.len() / 2);
+            boxes.push(ColorBox::new(colors));
+            boxes.push(ColorBox::new(upper));
+        }
+
+        boxes.iter().map(ColorBox::mean).collect()
+    }
+}
+
+const OCTREE_DEPTH: usize = 8;
+
+#[derive(Default)]
+struct OctreeNode {
+    /// Indices of the children in the octree's node list. The root node is never anyone's child,
+    /// so 0 means there's no child.
+    children: [usize; 8],
+    /// The sum of all Oklab colors within this node's subtree.
+    sum: [f32; 3],
+    count: usize,
+}
+
+impl OctreeNode {
+    fn is_leaf(&self) -> bool {
+        self.children.iter().all(|&child| child == 0)
+    }
+}
+
+/// Octree quantization. Colors are sorted into the tree by their sRGB bits, but averaged in
+/// Oklab.
+pub struct Octree;
+
+impl PaletteExtractor for Octree {
+    fn extract(&self, image: &Image<Oklab>, colors: usize) -> Vec<Oklab> {
+        let mut nodes = vec![OctreeNode::default()];
+        // Nodes with children, grouped by depth.
+        let mut reducible = vec![vec![]; OCTREE_DEPTH];
+        let mut leaf_count = 0;
+
+        for &color in &image.pixels {
+            let [r, g, b] = color.to_linear().to_srgb().to_array();
+            let observation = [color.l, color.a, color.b];
+            let mut node = 0;
+            for (depth, level) in reducible.iter_mut().enumerate() {
+                let shift = OCTREE_DEPTH - 1 - depth;
+                let octant = usize::from((r >> shift) & 1) << 2
+                    | usize::from((g >> shift) & 1) << 1
+                    | usize::from((b >> shift) & 1);
+
+                nodes[node].count += 1;
+                for (sum, x) in nodes[node].sum.iter_mut().zip(observation) {
+                    *sum += x;
+                }
+
+                if nodes[node].children[octant] == 0 {
+                    if nodes[node].is_leaf() {
+                        level.push(node);
+                    }
+                    nodes.push(OctreeNode::default());
+                    nodes[node].children[octant] = nodes.len() - 1;
+                    if depth == OCTREE_DEPTH - 1 {
+                        leaf_count += 1;
+                    }
+                }
+                node = nodes[node].children[octant];
+            }
+            nodes[node].count += 1;
+            for (sum, x) in nodes[node].sum.iter_mut().zip(observation) {
+                *sum += x;
+            }
+        }
+
+        // Merge the least popular nodes into their parents, deepest first, until the palette
+        // fits. Merging a node always turns it into a leaf, because all nodes below it have
+        // already been merged.
+        for level in &mut reducible {
+            level.sort_unstable_by_key(|&node| std::cmp::Reverse(nodes[node].count));
+        }
+        for level in reducible.iter_mut().rev() {
+            while leaf_count > colors {
+                let Some(node) = level.pop() else { break };
+                let children = nodes[node]
+                    .children
+                    .iter()
+                    .filter(|&&child| child != 0)
+                    .count();
+                nodes[node].children = [0; 8];
+                leaf_count -= children - 1;
+            }
+        }
+
+        let mut palette = Vec::with_capacity(leaf_count);
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let node = &nodes[node];
+            if node.is_leaf() {
+                if node.count > 0 {
+                    let [l, a, b] = node.sum.map(|x| x / node.count as f32);
+                    palette.push(Oklab { l, a, b });
+                }
+            } else {
+                stack.extend(node.children.iter().filter(|&&child| child != 0));
+            }
+        }
+        palette
+    }
+}
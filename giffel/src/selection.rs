@@ -0,0 +1,54 @@
+//! Frame selection syntax for the stitch command.
+
+use std::str::FromStr;
+
+/// A range of frames taken from one of the archives passed to stitch, written as
+/// `[archive:]start[-end]`. The archive index starts at 0 and defaults to the first archive, while
+/// frame indices start at 1 like everywhere else. Ranges are inclusive, and if `start` is greater
+/// than `end`, the frames are selected in reverse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRange {
+    pub archive: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl FrameRange {
+    /// Iterates over the selected frame indices.
+    pub fn indices(&self) -> Box<dyn Iterator<Item = usize>> {
+        if self.start <= self.end {
+            Box::new(self.start..=self.end)
+        } else {
+            Box::new((self.end..=self.start).rev())
+        }
+    }
+}
+
+impl FromStr for FrameRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_number = |n: &str| {
+            n.parse::<usize>()
+                .map_err(|_| format!("invalid number {n:?} in frame selection {s:?}"))
+        };
+
+        let (archive, range) = match s.split_once(':') {
+            Some((archive, range)) => (parse_number(archive)?, range),
+            None => (0, s),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse_number(start)?, parse_number(end)?),
+            None => {
+                let index = parse_number(range)?;
+                (index, index)
+            }
+        };
+
+        Ok(Self {
+            archive,
+            start,
+            end,
+        })
+    }
+}
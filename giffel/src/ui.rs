@@ -0,0 +1,69 @@
+//! Status messages and progress bars.
+
+use std::{
+    io::{IsTerminal, Stderr},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use pbr::ProgressBar;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static SHOW_PROGRESS: AtomicBool = AtomicBool::new(true);
+
+/// When to draw progress bars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressMode {
+    /// Only when both stdout and stderr are terminals.
+    Auto,
+    Always,
+    Never,
+}
+
+/// Sets up output verbosity. `quiet` takes precedence over `progress`.
+pub fn init(quiet: bool, progress: ProgressMode) {
+    let show_progress = !quiet
+        && match progress {
+            ProgressMode::Auto => {
+                std::io::stdout().is_terminal() && std::io::stderr().is_terminal()
+            }
+            ProgressMode::Always => true,
+            ProgressMode::Never => false,
+        };
+    QUIET.store(quiet, Ordering::Relaxed);
+    SHOW_PROGRESS.store(show_progress, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Prints a status message to stderr, unless `--quiet` was passed. Errors and warnings should use
+/// `eprintln!` instead, so that they're always shown.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::ui::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+pub(crate) use status;
+
+/// A progress bar that may or may not be drawn, depending on the output settings.
+pub struct Progress(Option<ProgressBar<Stderr>>);
+
+impl Progress {
+    pub fn new(max: u64) -> Self {
+        Self(SHOW_PROGRESS.load(Ordering::Relaxed).then(|| {
+            let mut bar = ProgressBar::on(std::io::stderr(), max);
+            bar.set_max_refresh_rate(Some(Duration::from_millis(20)));
+            bar
+        }))
+    }
+
+    pub fn inc(&mut self) {
+        if let Some(bar) = &mut self.0 {
+            bar.inc();
+        }
+    }
+}
@@ -1,5 +1,9 @@
-use std::process::Command;
+use std::{
+    io,
+    process::{Command, ExitStatus},
+};
 
+use thiserror::Error;
 use tracing::{debug, info_span};
 
 use crate::config::{AnimationConfig, FrameCountSource};
@@ -10,34 +14,135 @@ pub struct AnimationInfo {
     pub fps: f64,
     pub wave_count: f64,
     pub frame_count: usize,
+    /// The most frames a render of the animation may have. No more than `frame_count`.
+    pub max_output_frames: usize,
+    pub version: Option<String>,
 }
 
 impl AnimationInfo {
-    /// Resolves animation info from the given config.
-    pub fn from_config(config: &AnimationConfig) -> Self {
-        Self {
+    /// Resolves animation info from the given config. Renders are limited to `max_output_frames`
+    /// frames, or to the animation's frame count if there's no lower limit.
+    pub fn from_config(
+        config: &AnimationConfig,
+        max_output_frames: Option<usize>,
+    ) -> Result<Self, AnimationInfoError> {
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(AnimationInfoError::Invalid(problems.join(", ")));
+        }
+        let frame_count = config.frame_count.resolve()?;
+        if frame_count < 2 {
+            return Err(FrameCountError::TooFewFrames(frame_count).into());
+        }
+        Ok(Self {
             fps: config.fps,
             wave_count: config.wave_count,
-            frame_count: config.frame_count.resolve(),
-        }
+            frame_count,
+            max_output_frames: max_output_frames.map_or(frame_count, |max| max.min(frame_count)),
+            version: config.version.clone(),
+        })
     }
 
-    pub fn minimum_bpm(&self) -> f64 {
+    /// Returns the tempo the animation plays at on its own, showing every one of its frames.
+    pub fn natural_bpm(&self) -> f64 {
         self.wave_count * self.fps * 60.0 / self.frame_count as f64
     }
 
+    /// Returns the slowest supported tempo, at which a render has `max_output_frames` frames.
+    pub fn minimum_bpm(&self) -> f64 {
+        self.wave_count * self.fps * 60.0 / self.max_output_frames as f64
+    }
+
+    /// Returns the fastest supported tempo, at which the animation is squeezed into two frames.
+    pub fn maximum_bpm(&self) -> f64 {
+        self.wave_count * self.fps * 60.0 / 2.0
+    }
+
+    /// Returns all tempos that render to distinct animations, from slowest to fastest. Any other
+    /// tempo is quantized to one of these.
+    pub fn supported_bpms(&self) -> impl Iterator<Item = f64> + '_ {
+        (2..=self.max_output_frames)
+            .rev()
+            .map(|frame_count| self.wave_count * self.fps * 60.0 / frame_count as f64)
+    }
+
     pub fn quantize_bpm_to_nearest_supported(&self, bpm: f64) -> f64 {
         let unrounded_frame_count = self.wave_count * self.fps * 60.0 / bpm;
-        let frame_count = unrounded_frame_count.floor();
+        // NOTE: Supported tempos divide evenly into a whole number of frames, but floating point
+        // error can put the quotient just below it. The render service counts frames with the same
+        // leeway, so that both agree on the frame count.
+        let frame_count = (unrounded_frame_count + 1e-6).floor();
         self.wave_count * self.fps * 60.0 / frame_count
     }
+
+    /// Quantizes a tempo to the nearest supported one, or returns an error describing the
+    /// supported range if the tempo falls outside of it.
+    pub fn clamp_bpm(&self, bpm: f64) -> Result<f64, BpmError> {
+        // Checked before quantizing, which divides by the tempo.
+        if !bpm.is_finite() || bpm <= 0.0 {
+            return Err(BpmError::Invalid { bpm });
+        }
+        let quantized = self.quantize_bpm_to_nearest_supported(bpm);
+        let (minimum, maximum) = (self.minimum_bpm(), self.maximum_bpm());
+        if quantized < minimum {
+            Err(BpmError::TooSlow {
+                bpm,
+                minimum,
+                maximum,
+            })
+        } else if quantized > maximum {
+            Err(BpmError::TooFast {
+                bpm,
+                minimum,
+                maximum,
+            })
+        } else {
+            Ok(quantized)
+        }
+    }
+}
+
+/// A tempo the animation cannot be rendered at.
+#[derive(Debug, Error)]
+pub enum BpmError {
+    #[error("{bpm} is not a valid tempo. The tempo must be a positive number of BPM.")]
+    Invalid { bpm: f64 },
+    #[error(
+        "{bpm} BPM is too slow. This animation can only be rendered at {minimum:.2} to \
+         {maximum:.2} BPM."
+    )]
+    TooSlow {
+        bpm: f64,
+        minimum: f64,
+        maximum: f64,
+    },
+    #[error(
+        "{bpm} BPM is too fast. This animation can only be rendered at {minimum:.2} to \
+         {maximum:.2} BPM."
+    )]
+    TooFast {
+        bpm: f64,
+        minimum: f64,
+        maximum: f64,
+    },
+}
+
+impl BpmError {
+    /// Returns the error's code in JSON error responses.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Invalid { .. } => "BAD_BPM",
+            Self::TooSlow { .. } => "SPEED_TOO_SLOW",
+            Self::TooFast { .. } => "SPEED_TOO_FAST",
+        }
+    }
 }
 
 impl FrameCountSource {
-    pub fn resolve(&self) -> usize {
+    pub fn resolve(&self) -> Result<usize, FrameCountError> {
         let _span = info_span!("resolve_frame_count");
         match self {
-            FrameCountSource::Hardcoded { hardcoded } => *hardcoded,
+            FrameCountSource::Hardcoded { hardcoded } => Ok(*hardcoded),
             FrameCountSource::Command { command, flags } => {
                 debug!(
                     ?command,
@@ -47,16 +152,185 @@ impl FrameCountSource {
                 let output = Command::new(command)
                     .args(flags)
                     .output()
-                    .expect("failed to run command for determining the frame count");
-                assert!(
-                    output.status.success(),
-                    "frame count command returned with non-zero exit code"
-                );
-                String::from_utf8_lossy(&output.stdout)
-                    .trim()
+                    .map_err(FrameCountError::CommandSpawn)?;
+                if !output.status.success() {
+                    return Err(FrameCountError::CommandFailed {
+                        status: output.status,
+                        stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+                    });
+                }
+                let stdout = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+                stdout
                     .parse()
-                    .expect("cannot parse frame count command output as a number")
+                    .map_err(|_| FrameCountError::CommandOutput(stdout))
+            }
+            FrameCountSource::Directory { path, extension } => {
+                debug!(?path, extension, "counting frames in directory");
+                let mut count = 0;
+                for entry in std::fs::read_dir(path).map_err(FrameCountError::Directory)? {
+                    let path = entry.map_err(FrameCountError::Directory)?.path();
+                    if path.is_file() && path.extension() == Some(extension.as_ref()) {
+                        count += 1;
+                    }
+                }
+                Ok(count)
             }
         }
     }
 }
+
+/// A reason why an animation's info couldn't be resolved.
+#[derive(Debug, Error)]
+pub enum AnimationInfoError {
+    #[error("invalid animation config: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    FrameCount(#[from] FrameCountError),
+}
+
+/// A reason why the number of frames in an animation couldn't be determined.
+#[derive(Debug, Error)]
+pub enum FrameCountError {
+    #[error("cannot run the frame count command: {0}")]
+    CommandSpawn(io::Error),
+    #[error("the frame count command failed ({status}): {stderr}")]
+    CommandFailed { status: ExitStatus, stderr: String },
+    #[error("the frame count command printed {0:?}, which is not a number")]
+    CommandOutput(String),
+    #[error("cannot read the frames directory: {0}")]
+    Directory(io::Error),
+    #[error("the animation must have at least 2 frames, but it has {0}")]
+    TooFewFrames(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnimationInfo, AnimationInfoError, BpmError};
+    use crate::config::{AnimationConfig, FrameCountSource};
+
+    /// An animation that plays at 60 BPM, and can be sped up to 3000 BPM.
+    fn info() -> AnimationInfo {
+        AnimationInfo {
+            fps: 50.0,
+            wave_count: 2.0,
+            frame_count: 100,
+            max_output_frames: 100,
+            version: None,
+        }
+    }
+
+    /// The same animation, with renders limited to 40 frames.
+    fn capped_info() -> AnimationInfo {
+        AnimationInfo {
+            max_output_frames: 40,
+            ..info()
+        }
+    }
+
+    #[test]
+    fn from_config_rejects_invalid_configs() {
+        let config = AnimationConfig {
+            fps: 0.0,
+            wave_count: 2.0,
+            frame_count: FrameCountSource::Hardcoded { hardcoded: 100 },
+            version: None,
+        };
+        assert!(matches!(
+            AnimationInfo::from_config(&config, None),
+            Err(AnimationInfoError::Invalid(_))
+        ));
+        let config = AnimationConfig {
+            fps: 50.0,
+            ..config
+        };
+        assert_eq!(
+            AnimationInfo::from_config(&config, Some(40))
+                .unwrap()
+                .max_output_frames,
+            40
+        );
+    }
+
+    #[test]
+    fn bpm_range() {
+        let info = info();
+        assert_eq!(info.minimum_bpm(), 60.0);
+        assert_eq!(info.maximum_bpm(), 3000.0);
+    }
+
+    #[test]
+    fn supported_bpms() {
+        let info = info();
+        let supported: Vec<_> = info.supported_bpms().collect();
+        assert_eq!(supported.len(), 99);
+        assert_eq!(supported.first(), Some(&60.0));
+        assert_eq!(supported.last(), Some(&3000.0));
+        assert!(supported.windows(2).all(|pair| pair[0] < pair[1]));
+        for bpm in supported {
+            assert_eq!(info.quantize_bpm_to_nearest_supported(bpm), bpm);
+        }
+    }
+
+    #[test]
+    fn max_output_frames_raises_minimum_bpm() {
+        let info = capped_info();
+        assert_eq!(info.natural_bpm(), 60.0);
+        assert_eq!(info.minimum_bpm(), 150.0);
+        assert_eq!(info.maximum_bpm(), 3000.0);
+        let supported: Vec<_> = info.supported_bpms().collect();
+        assert_eq!(supported.len(), 39);
+        assert_eq!(supported.first(), Some(&150.0));
+        assert_eq!(info.clamp_bpm(150.0).unwrap(), 150.0);
+        assert!(matches!(
+            info.clamp_bpm(140.0),
+            Err(BpmError::TooSlow { minimum, .. }) if minimum == 150.0
+        ));
+    }
+
+    #[test]
+    fn clamp_bpm_rejects_invalid_tempos() {
+        for bpm in [0.0, -0.0, -60.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(
+                matches!(info().clamp_bpm(bpm), Err(BpmError::Invalid { .. })),
+                "{bpm} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn clamp_bpm_quantizes_within_range() {
+        let info = info();
+        assert_eq!(info.clamp_bpm(60.0).unwrap(), 60.0);
+        assert_eq!(info.clamp_bpm(3000.0).unwrap(), 3000.0);
+        assert_eq!(info.clamp_bpm(100.0).unwrap(), 100.0);
+        // Tempos are rounded up to the next one with a whole number of frames, so anything that
+        // would take fewer than 101 frames still renders.
+        assert_eq!(info.clamp_bpm(59.41).unwrap(), 60.0);
+        assert_eq!(info.clamp_bpm(2999.0).unwrap(), 3000.0);
+    }
+
+    #[test]
+    fn clamp_bpm_rejects_tempos_out_of_range() {
+        let info = info();
+        for bpm in [59.4, 30.0, 0.001] {
+            assert!(
+                matches!(
+                    info.clamp_bpm(bpm),
+                    Err(BpmError::TooSlow { minimum, maximum, .. })
+                        if minimum == 60.0 && maximum == 3000.0
+                ),
+                "{bpm} should be too slow"
+            );
+        }
+        for bpm in [3000.01, 6000.0, 1e9] {
+            assert!(
+                matches!(
+                    info.clamp_bpm(bpm),
+                    Err(BpmError::TooFast { minimum, maximum, .. })
+                        if minimum == 60.0 && maximum == 3000.0
+                ),
+                "{bpm} should be too fast"
+            );
+        }
+    }
+}
@@ -1,17 +1,28 @@
 //! Render cache management service.
 
 use std::{
+    collections::HashSet,
+    io::ErrorKind,
     path::{Path, PathBuf},
-    sync::Arc,
-    time::SystemTime,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, SystemTime},
 };
 
-use parking_lot::Mutex;
-use serde::Deserialize;
-use tokio::sync::{mpsc, oneshot};
-use tracing::{debug, error, info, info_span};
+use dashmap::DashMap;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot, RwLock as AsyncRwLock};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
-use crate::{common::Error, render_service::RenderServiceHandle};
+use crate::{
+    common::{render_key, Error, Format, RenderOptions},
+    memory_cache::MemoryCache,
+    metrics::Metrics,
+    render_service::RenderServiceHandle,
+};
 
 #[derive(Clone, Deserialize)]
 pub struct CacheServiceConfig {
@@ -21,13 +32,143 @@ pub struct CacheServiceConfig {
     pub database: PathBuf,
     /// When to start purging the cache (in bytes taken up by GIFs.)
     pub limit: u64,
-    /// When to stop removing old GIFs.
+    /// When to stop removing old GIFs. Must be less than `limit`.
     pub purge_limit: u64,
+    /// When to start purging the cache, in number of GIFs. Only the total size counts if this is
+    /// not set.
+    #[serde(default)]
+    pub max_files: Option<usize>,
+    /// How many GIFs to leave when purging the cache because `max_files` was exceeded. Defaults
+    /// to `max_files`.
+    #[serde(default)]
+    pub purge_to_files: Option<usize>,
     /// How many GIFs to remove at a time.
     pub purge_max_count: usize,
+    /// How many bytes of recently served GIFs to keep in memory, so that popular ones don't have
+    /// to be read from disk every time. Disabled if 0.
+    #[serde(default)]
+    pub memory_cache_size: u64,
+    /// How often to check whether the cache needs purging, in seconds. The cache can grow past
+    /// `limit` in between.
+    #[serde(default = "default_gc_interval")]
+    pub gc_interval: u64,
+    /// Which GIFs are removed first when purging the cache.
+    #[serde(default)]
+    pub eviction: Eviction,
+    /// How long a GIF may go without being requested before it's removed from the cache,
+    /// regardless of how much space the cache takes up. GIFs are kept until the cache is full if
+    /// this is not set.
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+    /// Tempos to render into the cache in the background when the server starts, so that they
+    /// can be served right away.
+    #[serde(default)]
+    pub warm_bpms: Vec<f64>,
+    /// A range of tempos to render into the cache on startup, eg. `[120, 140]`. Every supported
+    /// tempo within the range (inclusive) is rendered, in addition to `warm_bpms`.
+    #[serde(default)]
+    pub warm_bpm_range: Option<(f64, f64)>,
+    /// The formats that tempos are rendered in when warming the cache.
+    #[serde(default = "default_warm_formats")]
+    pub warm_formats: Vec<Format>,
+}
+
+fn default_gc_interval() -> u64 {
+    60
+}
+
+fn default_warm_formats() -> Vec<Format> {
+    vec![Format::Gif]
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eviction {
+    /// Remove the GIFs that were least recently requested.
+    #[default]
+    Recency,
+    /// Remove the GIFs that were requested the fewest times, and of those, the least recently
+    /// requested ones.
+    Frequency,
+}
+
+impl Eviction {
+    /// The `ORDER BY` clause sorting GIFs in the order they should be removed.
+    fn order_by(self) -> &'static str {
+        match self {
+            Self::Recency => "time ASC",
+            Self::Frequency => "hits ASC, time ASC",
+        }
+    }
+}
+
+/// The settings deciding when GIFs are removed from the cache. Unlike the rest of the config, they
+/// can be changed while the server is running.
+#[derive(Clone)]
+struct CacheLimits {
+    limit: u64,
+    purge_limit: u64,
+    max_files: Option<usize>,
+    purge_to_files: Option<usize>,
+    purge_max_count: usize,
+    eviction: Eviction,
+    ttl_seconds: Option<u64>,
+}
+
+impl From<&CacheServiceConfig> for CacheLimits {
+    fn from(config: &CacheServiceConfig) -> Self {
+        Self {
+            limit: config.limit,
+            purge_limit: config.purge_limit,
+            max_files: config.max_files,
+            purge_to_files: config.purge_to_files,
+            purge_max_count: config.purge_max_count,
+            eviction: config.eviction,
+            ttl_seconds: config.ttl_seconds,
+        }
+    }
 }
 
 impl CacheServiceConfig {
+    /// Checks the config for mistakes that would otherwise only surface once something is
+    /// cached, returning a description of each one.
+    ///
+    /// NOTE: This creates the cache directory if it doesn't exist yet, as whether a directory is
+    /// writable can only be told by writing to it.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.purge_limit >= self.limit {
+            problems.push(format!(
+                "`purge_limit` ({}) must be less than `limit` ({}), or purging cannot bring the \
+                 cache back under the limit",
+                self.purge_limit, self.limit
+            ));
+        }
+        if let (Some(max_files), Some(purge_to_files)) = (self.max_files, self.purge_to_files) {
+            if purge_to_files > max_files {
+                problems.push(format!(
+                    "`purge_to_files` ({purge_to_files}) must not be greater than `max_files` \
+                     ({max_files})"
+                ));
+            }
+        }
+        if self.purge_max_count == 0 {
+            problems
+                .push("`purge_max_count` must be at least 1, or nothing can ever be purged".into());
+        }
+        let probe = self.cache_dir.join(".smugdancer-write-test");
+        let writable = std::fs::create_dir_all(&self.cache_dir)
+            .and_then(|_| std::fs::write(&probe, []))
+            .and_then(|_| std::fs::remove_file(&probe));
+        if let Err(error) = writable {
+            problems.push(format!(
+                "the cache directory ({}) is not writable: {error}",
+                self.cache_dir.display()
+            ));
+        }
+        problems
+    }
+
     pub fn setup(&self) -> Result<rusqlite::Connection, Error> {
         debug!("creating cache directories");
         std::fs::create_dir_all(&self.cache_dir).map_err(Error::DirSetup)?;
@@ -38,151 +179,444 @@ impl CacheServiceConfig {
             r#"
                 CREATE TABLE IF NOT EXISTS usage_time (
                     file    TEXT NOT NULL UNIQUE,
-                    time    INTEGER NOT NULL
+                    time    INTEGER NOT NULL,
+                    hits    INTEGER NOT NULL DEFAULT 0,
+                    size    INTEGER NOT NULL DEFAULT 0,
+                    width   INTEGER,
+                    height  INTEGER,
+                    frames  INTEGER,
+                    format  TEXT
                 )
             "#,
             (),
         )?;
+        // NOTE: Databases created by older versions need the newer columns added.
+        add_column_if_missing(&database, "hits", "INTEGER NOT NULL DEFAULT 0")?;
+        if add_column_if_missing(&database, "size", "INTEGER NOT NULL DEFAULT 0")? {
+            debug!("recording sizes of existing files in cache database");
+            let files: Vec<String> = database
+                .prepare("SELECT file FROM usage_time")?
+                .query_map((), |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            let mut stmt = database.prepare("UPDATE usage_time SET size = ?2 WHERE file = ?1")?;
+            for file in files {
+                if let Ok(metadata) = Path::new(&file).metadata() {
+                    stmt.execute((file, metadata.len()))?;
+                }
+            }
+        }
+        // NOTE: The dimensions of files cached before they were recorded are filled in the next
+        // time the files are requested.
+        add_column_if_missing(&database, "width", "INTEGER")?;
+        add_column_if_missing(&database, "height", "INTEGER")?;
+        add_column_if_missing(&database, "frames", "INTEGER")?;
+        add_column_if_missing(&database, "format", "TEXT")?;
         Ok(database)
     }
 }
 
+/// Adds a column to the `usage_time` table if it doesn't have it yet. Returns whether the column
+/// was added.
+fn add_column_if_missing(
+    database: &rusqlite::Connection,
+    name: &str,
+    definition: &str,
+) -> Result<bool, Error> {
+    let exists: bool = database.query_row(
+        r#"
+            SELECT COUNT(*) > 0
+            FROM pragma_table_info('usage_time')
+            WHERE name = ?1
+        "#,
+        (name,),
+        |row| row.get(0),
+    )?;
+    if !exists {
+        debug!(name, "adding column to cache database");
+        database.execute(
+            &format!("ALTER TABLE usage_time ADD COLUMN {name} {definition}"),
+            (),
+        )?;
+    }
+    Ok(!exists)
+}
+
+/// The paths of all files in the cache. This is kept in memory so that checking whether an
+/// animation is cached doesn't need to touch the file system, and is loaded from the database on
+/// startup.
+type CacheIndex = Arc<Mutex<HashSet<PathBuf>>>;
+
+/// Locks on each file in the cache. Requests reading a file from disk share its lock, while
+/// writing or removing the file takes it exclusively.
+type FileLocks = DashMap<PathBuf, Weak<AsyncRwLock<()>>>;
+
+/// Loads the paths of all cached files from the database.
+fn load_index(database: &rusqlite::Connection) -> Result<HashSet<PathBuf>, Error> {
+    let files: Vec<String> = database
+        .prepare("SELECT file FROM usage_time")?
+        .query_map((), |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(files.into_iter().map(PathBuf::from).collect())
+}
+
 pub struct GifService {
     config: CacheServiceConfig,
+    limits: Arc<RwLock<CacheLimits>>,
+    /// The version of the animation's frames, which is part of the names of cached files.
+    version: Option<String>,
     render_service: RenderServiceHandle,
     database: Arc<Mutex<rusqlite::Connection>>,
+    index: CacheIndex,
+    memory: Arc<Mutex<MemoryCache>>,
+    /// Locks keeping files from being removed while they're read, and serializing writes.
+    file_locks: FileLocks,
+    metrics: Arc<Metrics>,
 }
 
 impl GifService {
     pub fn spawn(
         config: CacheServiceConfig,
+        version: Option<String>,
         render_service: RenderServiceHandle,
+        metrics: Arc<Metrics>,
     ) -> Result<CacheServiceHandle, Error> {
-        let (requests_tx, mut requests_rx) = mpsc::channel(32);
-
-        let database = config.setup()?;
-        let database = Arc::new(Mutex::new(database));
+        let (requests_tx, mut requests_rx) = mpsc::channel::<GifRequest>(32);
+        let service = Arc::new(Self::new(config, version, render_service, metrics)?);
+        let handle = CacheServiceHandle {
+            requests: requests_tx,
+            config: service.config.clone(),
+            limits: Arc::clone(&service.limits),
+            version: service.version.clone(),
+            database: Arc::clone(&service.database),
+            index: Arc::clone(&service.index),
+            memory: Arc::clone(&service.memory),
+        };
 
-        let service = Arc::new(GifService {
-            config,
-            render_service,
-            database,
+        tokio::spawn({
+            let service = Arc::clone(&service);
+            async move {
+                let period = Duration::from_secs(service.config.gc_interval.max(1));
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    // GC errors are non-fatal.
+                    if let Err(error) = service.collect_garbage().await {
+                        error!("{error}")
+                    }
+                }
+            }
         });
         tokio::spawn(async move {
             info!("cache task is ready");
             while let Some(request) = requests_rx.recv().await {
                 let service = Arc::clone(&service);
-                tokio::spawn(async move { service.handle_request(request).await });
+                let span = request.span.clone();
+                tokio::spawn(async move { service.handle_request(request).await }.instrument(span));
             }
         });
 
-        Ok(CacheServiceHandle {
-            requests: requests_tx,
+        Ok(handle)
+    }
+
+    /// Opens the cache database and loads the index of cached files from it.
+    fn new(
+        config: CacheServiceConfig,
+        version: Option<String>,
+        render_service: RenderServiceHandle,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Error> {
+        let database = config.setup()?;
+        // NOTE: The clock may have gone backwards while the server was down, so times are kept
+        // from going below the ones already in the database.
+        let latest_time: Option<u64> =
+            database.query_row("SELECT MAX(time) FROM usage_time", (), |row| row.get(0))?;
+        LATEST_TIME.fetch_max(latest_time.unwrap_or(0), Ordering::Relaxed);
+        let index = Arc::new(Mutex::new(load_index(&database)?));
+        debug!(count = index.lock().len(), "loaded cache index");
+        let database = Arc::new(Mutex::new(database));
+        let limits = Arc::new(RwLock::new(CacheLimits::from(&config)));
+        let memory = Arc::new(Mutex::new(MemoryCache::new(config.memory_cache_size)));
+
+        Ok(GifService {
+            config,
+            limits,
+            version,
+            render_service,
+            database,
+            index,
+            memory,
+            file_locks: FileLocks::default(),
+            metrics,
         })
     }
 
     async fn handle_request(&self, request: GifRequest) {
-        let GifRequest { speed, responder } = request;
-        let _ = responder.send(self.handle_request_inner(speed).await);
+        let GifRequest {
+            output_frames,
+            format,
+            options,
+            mut responder,
+            ..
+        } = request;
+        let result = self
+            .handle_request_inner(output_frames, format, options, &mut responder)
+            .await;
+        let _ = responder.send(result);
     }
 
-    async fn handle_request_inner(&self, speed: f64) -> Result<Vec<u8>, Error> {
-        debug!(speed, "handling cache request");
-        let cached_filename = self.config.cache_dir.join(Self::get_cached_filename(speed));
+    async fn handle_request_inner(
+        &self,
+        output_frames: usize,
+        format: Format,
+        options: RenderOptions,
+        responder: &mut oneshot::Sender<Result<CachedFile, Error>>,
+    ) -> Result<CachedFile, Error> {
+        debug!(output_frames, ?format, ?options, "handling cache request");
+        let cached_filename = self.config.cache_dir.join(render_key(
+            self.version.as_deref(),
+            output_frames,
+            format,
+            options,
+        ));
 
-        let file = if !cached_filename.exists() {
-            // GC errors are non-fatal.
-            if let Err(error) = self.collect_garbage().await {
-                error!("{error}")
-            }
+        let cached = self.read_cached(&cached_filename, format).await?;
+        let (file, queue_position) = if let Some(gif) = cached {
+            self.metrics.cache_hits.fetch_add(1, Ordering::Relaxed);
+            (gif, None)
+        } else {
+            self.metrics.cache_misses.fetch_add(1, Ordering::Relaxed);
 
-            debug!("this speed is not cached yet, rendering");
-            let (gif, position_in_queue) = self
-                .render_service
-                .render_speed(speed)
-                .await
-                .map_err(Error::RenderFailed)?;
-            if position_in_queue == 0 {
-                tokio::fs::write(&cached_filename, &gif)
-                    .await
-                    .map_err(Error::CannotWriteGif)?;
-            }
+            debug!("this animation is not cached yet, rendering");
+            // NOTE: Concurrent requests for the same file all join the same render, which is only
+            // given up on once every one of them has gone away. Going away here only removes this
+            // client from the render's queue.
+            let render = self.render_service.render(output_frames, format, options);
+            let (gif, position_in_queue) = tokio::select! {
+                result = render => result.map_err(Error::RenderFailed)?,
+                _ = responder.closed() => {
+                    debug!("client went away before the render finished");
+                    return Err(Error::Cancelled);
+                }
+            };
+            self.write_cached(&cached_filename, format, &gif).await?;
 
-            gif
-        } else {
-            tokio::fs::read(&cached_filename)
-                .await
-                .map_err(Error::CannotReadGif)?
+            (gif, Some(position_in_queue))
         };
 
+        self.metrics
+            .cache_bytes_served
+            .fetch_add(file.len() as u64, Ordering::Relaxed);
+
         // NOTE: Result is ignored because the task shouldn't panic.
         // If it does, the panic will be logged.
         let _ = tokio::task::spawn_blocking({
             let database = Arc::clone(&self.database);
 
+            let size = file.len() as u64;
+            let (width, height) = format.dimensions(&file).unzip();
             let file = cached_filename.clone();
             let file = file.to_str().ok_or(Error::InvalidUtf8)?.to_owned();
-            let time = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .map_err(|_| Error::ClockWentBackwards)?
-                .as_secs();
+            let time = unix_time();
 
             move || {
                 let database = database.lock();
                 let mut stmt = database
                     .prepare_cached(
                         r#"
-                            INSERT OR REPLACE
-                            INTO usage_time (file, time)
-                            VALUES (?1, ?2)
+                            INSERT INTO usage_time
+                                (file, time, hits, size, width, height, frames, format)
+                            VALUES (?1, ?2, 1, ?3, ?4, ?5, ?6, ?7)
+                            ON CONFLICT (file)
+                            DO UPDATE SET
+                                time = excluded.time,
+                                hits = hits + 1,
+                                size = excluded.size,
+                                width = excluded.width,
+                                height = excluded.height,
+                                frames = excluded.frames,
+                                format = excluded.format
                         "#,
                     )
                     .expect("cannot prepare SQL statement");
-                stmt.execute((file, time))
+                stmt.execute((
+                    file,
+                    time,
+                    size,
+                    width,
+                    height,
+                    output_frames,
+                    format.name(),
+                ))
             }
         })
         .await;
 
-        Ok(file)
+        Ok(CachedFile {
+            data: file,
+            queue_position,
+        })
     }
 
-    fn get_cached_filename(speed: f64) -> String {
-        let bits = speed.to_bits();
-        format!("{bits:x}.gif")
+    /// Writes a rendered file to the cache, unless another request for it already has.
+    async fn write_cached(&self, path: &Path, format: Format, gif: &[u8]) -> Result<(), Error> {
+        // NOTE: Every request waiting for a render gets its result, but only the first one to
+        // take the lock writes it; the rest find it in the index once it's their turn.
+        let lock = self.file_lock(path);
+        let _guard = lock.write().await;
+        if self.read_cached_locked(path, format).await?.is_some() {
+            return Ok(());
+        }
+        // NOTE: The file is written under a temporary name first, so that a server shutting
+        // down mid-write doesn't leave a truncated GIF in the cache.
+        let partial_filename = path.with_extension("partial");
+        tokio::fs::write(&partial_filename, gif)
+            .await
+            .map_err(Error::CannotWriteGif)?;
+        tokio::fs::rename(&partial_filename, path)
+            .await
+            .map_err(Error::CannotWriteGif)?;
+        self.index.lock().insert(path.to_owned());
+        self.memory.lock().insert(path.to_owned(), gif);
+        self.metrics.cache_writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
     }
 
-    async fn collect_garbage(&self) -> Result<(), Error> {
-        let mut entries = vec![];
-        let mut read_dir = tokio::fs::read_dir(&self.config.cache_dir)
-            .await
-            .map_err(Error::CollectGarbage)?;
-        while let Some(entry) = read_dir.next_entry().await.map_err(Error::CollectGarbage)? {
-            let metadata = entry.metadata().await.map_err(Error::CollectGarbage)?;
-            entries.push((entry, metadata));
+    /// Returns the lock on the given file, creating a new one if nobody is holding it.
+    fn file_lock(&self, path: &Path) -> Arc<AsyncRwLock<()>> {
+        let mut entry = self.file_locks.entry(path.to_owned()).or_default();
+        match entry.upgrade() {
+            Some(lock) => lock,
+            None => {
+                let lock = Arc::new(AsyncRwLock::new(()));
+                *entry = Arc::downgrade(&lock);
+                lock
+            }
         }
+    }
+
+    /// Reads a file from the cache, or returns `None` if it isn't cached. Files that are damaged,
+    /// eg. because the server crashed while writing them, are removed so that they get rendered
+    /// again.
+    async fn read_cached(&self, path: &Path, format: Format) -> Result<Option<Vec<u8>>, Error> {
+        if !self.index.lock().contains(path) {
+            return Ok(None);
+        }
+        if let Some(gif) = self.memory.lock().get(path) {
+            return Ok(Some(gif));
+        }
+        // NOTE: The file is pinned while it's read, as removing a file that's being read fails on
+        // some platforms.
+        let lock = self.file_lock(path);
+        let _guard = lock.read().await;
+        self.read_cached_locked(path, format).await
+    }
+
+    /// Reads a file from the cache like `read_cached`, with its lock already held.
+    async fn read_cached_locked(
+        &self,
+        path: &Path,
+        format: Format,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        // NOTE: The file may have been removed while waiting for the lock.
+        if !self.index.lock().contains(path) {
+            return Ok(None);
+        }
+        if let Some(gif) = self.memory.lock().get(path) {
+            return Ok(Some(gif));
+        }
+        let gif = match tokio::fs::read(path).await {
+            Ok(gif) => gif,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                warn!(?path, "cached file has disappeared, rendering it again");
+                self.index.lock().remove(path);
+                return Ok(None);
+            }
+            Err(error) => return Err(Error::CannotReadGif(error)),
+        };
+        if !format.is_complete_file(&gif) {
+            self.index.lock().remove(path);
+            warn!(
+                ?path,
+                size = gif.len(),
+                "cached file is damaged, rendering it again"
+            );
+            if let Err(error) = tokio::fs::remove_file(path).await {
+                error!(?path, %error, "cannot remove damaged file");
+            }
+            return Ok(None);
+        }
+        self.memory.lock().insert(path.to_owned(), &gif);
+        Ok(Some(gif))
+    }
+
+    /// Removes GIFs that weren't requested within the configured TTL.
+    async fn expire(&self) -> Result<(), Error> {
+        let ttl = match self.limits.read().ttl_seconds {
+            Some(ttl) => ttl,
+            None => return Ok(()),
+        };
+        let cutoff = unix_time().saturating_sub(ttl);
+
+        let database = Arc::clone(&self.database);
+        let expired_files: Vec<String> = tokio::task::spawn_blocking(move || {
+            let database = database.lock();
+            let mut stmt = database
+                .prepare_cached(
+                    r#"
+                        SELECT file FROM usage_time
+                        WHERE time < ?1
+                    "#,
+                )
+                .expect("cannot prepare query");
+            stmt.query_map((cutoff,), |row| row.get(0))
+                .expect("cannot query rows")
+                .filter_map(|r| r.ok())
+                .collect()
+        })
+        .await
+        .map_err(|e| Error::DbQuery(e.to_string()))?;
+
+        if !expired_files.is_empty() {
+            info!(ttl, count = expired_files.len(), "removing expired GIFs");
+            self.remove_files(expired_files).await;
+        }
+        Ok(())
+    }
+
+    async fn collect_garbage(&self) -> Result<(), Error> {
+        self.expire().await?;
 
-        let mut total_size: u64 = entries.iter().map(|(_, metadata)| metadata.len()).sum();
-        if total_size >= self.config.limit {
+        let database = Arc::clone(&self.database);
+        let (mut total_count, mut total_size) =
+            tokio::task::spawn_blocking(move || totals(&database.lock()))
+                .await
+                .map_err(|e| Error::DbQuery(e.to_string()))??;
+        let limits = self.limits.read().clone();
+        let max_files = limits.max_files.unwrap_or(usize::MAX);
+        let purge_to_files = limits.purge_to_files.unwrap_or(max_files);
+        if total_size >= limits.limit || total_count > max_files {
             let _span = info_span!("cache_purge");
             info!(
-                self.config.limit,
-                total_size, "purging cache (limit was exceeded)"
+                limits.limit,
+                total_size, limits.max_files, total_count, "purging cache (limit was exceeded)"
             );
 
             let database = Arc::clone(&self.database);
-            let max_count = self.config.purge_max_count;
-            let oldest_files: Vec<String> = tokio::task::spawn_blocking(move || {
+            let max_count = limits.purge_max_count;
+            let eviction = limits.eviction;
+            let oldest_files: Vec<(String, u64)> = tokio::task::spawn_blocking(move || {
                 let database = database.lock();
                 let mut stmt = database
-                    .prepare_cached(
+                    .prepare_cached(&format!(
                         r#"
-                            SELECT file FROM usage_time
-                            ORDER BY time ASC
+                            SELECT file, size FROM usage_time
+                            ORDER BY {}
                             LIMIT ?1
                         "#,
-                    )
+                        eviction.order_by()
+                    ))
                     .expect("cannot prepare query");
-                stmt.query_map((max_count,), |row| row.get(0))
+                stmt.query_map((max_count,), |row| Ok((row.get(0)?, row.get(1)?)))
                     .expect("cannot query rows")
                     .filter_map(|r| r.ok())
                     .collect()
@@ -191,70 +625,245 @@ impl GifService {
             .map_err(|e| Error::DbQuery(e.to_string()))?;
 
             let mut to_remove = vec![];
-            for filename in oldest_files {
-                let path = Path::new(&filename);
-                if let Ok(metadata) = path.metadata() {
-                    to_remove.push(filename);
-                    total_size -= metadata.len();
-                    if total_size <= self.config.purge_limit {
-                        break;
-                    }
+            for (filename, size) in oldest_files {
+                to_remove.push(filename);
+                total_size = total_size.saturating_sub(size);
+                total_count -= 1;
+                if total_size <= limits.purge_limit && total_count <= purge_to_files {
+                    break;
                 }
             }
-            let mut removed = vec![];
-            for filename in to_remove {
-                match tokio::fs::remove_file(&filename)
-                    .await
-                    .map_err(Error::CollectGarbage)
-                {
-                    Ok(_) => {
-                        debug!(?filename, "removed file");
-                        removed.push(filename);
-                    }
-                    Err(error) => {
-                        debug!(?filename, %error, "cannot remove file")
-                    }
+            self.remove_files(to_remove).await;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the given GIFs from the cache directory and the database.
+    async fn remove_files(&self, filenames: Vec<String>) {
+        let mut removed = vec![];
+        for filename in filenames {
+            let path = Path::new(&filename);
+            // NOTE: Files that are being read or written right now are skipped, since removing a
+            // file that's being read fails on some platforms. They are tried again the next time
+            // garbage is collected. Holding the lock also keeps new requests from reading the file
+            // while it's being removed.
+            let lock = self.file_lock(path);
+            let _guard = match lock.try_write() {
+                Ok(guard) => guard,
+                Err(_) => {
+                    debug!(?filename, "file is in use, not removing it");
+                    continue;
+                }
+            };
+            match tokio::fs::remove_file(path).await {
+                Ok(_) => {
+                    debug!(?filename, "removed file");
+                    self.index.lock().remove(path);
+                    self.memory.lock().remove(path);
+                    removed.push(filename);
+                }
+                // NOTE: The file is already gone, so only its row in the database is left to
+                // remove. Otherwise it would keep coming up as expired.
+                Err(error) if error.kind() == ErrorKind::NotFound => {
+                    self.index.lock().remove(path);
+                    self.memory.lock().remove(path);
+                    removed.push(filename);
+                }
+                Err(error) => {
+                    debug!(?filename, %error, "cannot remove file")
                 }
             }
-            let database = Arc::clone(&self.database);
-            tokio::task::spawn_blocking(move || {
-                let database = database.lock();
-                let mut stmt = database
-                    .prepare_cached(
-                        r#"
+        }
+        let database = Arc::clone(&self.database);
+        tokio::task::spawn_blocking(move || {
+            let database = database.lock();
+            let mut stmt = database
+                .prepare_cached(
+                    r#"
                             DELETE FROM usage_time
                             WHERE file = ?1
                         "#,
-                    )
-                    .expect("cannot prepare deletion query");
-                for filename in removed {
-                    // NOTE: Should always succeed so we ignore the result.
-                    let _ = stmt.execute((filename,));
-                }
-            });
-        }
+                )
+                .expect("cannot prepare deletion query");
+            for filename in removed {
+                // NOTE: Should always succeed so we ignore the result.
+                let _ = stmt.execute((filename,));
+            }
+        });
+    }
+}
 
-        Ok(())
+/// The latest time returned by `unix_time`.
+static LATEST_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the current time as seconds since the Unix epoch. Usage times are only compared with
+/// each other, so if the system clock jumps backwards (eg. when it's corrected by NTP), the latest
+/// time seen so far is returned until the clock catches up.
+fn unix_time() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    LATEST_TIME.fetch_max(now, Ordering::Relaxed).max(now)
+}
+
+/// Returns the number of files in the cache and their total size.
+fn totals(database: &rusqlite::Connection) -> Result<(usize, u64), Error> {
+    Ok(database.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM usage_time",
+        (),
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?)
+}
+
+/// Removes every rendered animation from the cache directory and the database. Files that are
+/// still being written are left alone.
+pub fn purge(cache_dir: &Path, database: &rusqlite::Connection) -> Result<Purged, Error> {
+    let mut purged = Purged::default();
+    for entry in std::fs::read_dir(cache_dir).map_err(Error::Purge)? {
+        let entry = entry.map_err(Error::Purge)?;
+        let path = entry.path();
+        let metadata = entry.metadata().map_err(Error::Purge)?;
+        if !metadata.is_file() || path.extension() == Some("partial".as_ref()) {
+            continue;
+        }
+        std::fs::remove_file(&path).map_err(Error::Purge)?;
+        purged.files += 1;
+        purged.bytes += metadata.len();
     }
+    database.execute("DELETE FROM usage_time", ())?;
+    Ok(purged)
+}
+
+/// What was removed from the cache by a purge.
+#[derive(Debug, Default, Serialize)]
+pub struct Purged {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// How full the cache is, and how full it's allowed to get.
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub files: usize,
+    pub bytes: u64,
+    pub limit: u64,
+    pub purge_limit: u64,
+    pub max_files: Option<usize>,
+    pub purge_to_files: Option<usize>,
+}
+
+/// A rendered animation returned by the cache service.
+pub struct CachedFile {
+    pub data: Vec<u8>,
+    /// The requester's position in the render queue, or `None` if the file was already cached.
+    pub queue_position: Option<usize>,
 }
 
 struct GifRequest {
-    speed: f64,
-    responder: oneshot::Sender<Result<Vec<u8>, Error>>,
+    output_frames: usize,
+    format: Format,
+    options: RenderOptions,
+    responder: oneshot::Sender<Result<CachedFile, Error>>,
+    /// The span of the request, which the request is handled in.
+    span: Span,
 }
 
 #[derive(Clone)]
 pub struct CacheServiceHandle {
     requests: mpsc::Sender<GifRequest>,
+    config: CacheServiceConfig,
+    limits: Arc<RwLock<CacheLimits>>,
+    version: Option<String>,
+    database: Arc<Mutex<rusqlite::Connection>>,
+    index: CacheIndex,
+    memory: Arc<Mutex<MemoryCache>>,
 }
 
 impl CacheServiceHandle {
-    pub async fn request_speed(&self, speed: f64) -> Result<Vec<u8>, Error> {
+    /// Returns whether the cache service is still accepting requests.
+    pub fn is_running(&self) -> bool {
+        !self.requests.is_closed()
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.config.cache_dir
+    }
+
+    /// Returns how full the cache is.
+    pub async fn stats(&self) -> Result<CacheStats, Error> {
+        let database = Arc::clone(&self.database);
+        let (files, bytes) = tokio::task::spawn_blocking(move || totals(&database.lock()))
+            .await
+            .map_err(|e| Error::DbQuery(e.to_string()))??;
+        let limits = self.limits.read();
+        Ok(CacheStats {
+            files,
+            bytes,
+            limit: limits.limit,
+            purge_limit: limits.purge_limit,
+            max_files: limits.max_files,
+            purge_to_files: limits.purge_to_files,
+        })
+    }
+
+    /// Replaces the settings deciding when GIFs are removed with the ones from the given config.
+    /// They're picked up the next time garbage is collected; the rest of the config is ignored.
+    pub fn set_limits(&self, config: &CacheServiceConfig) {
+        *self.limits.write() = CacheLimits::from(config);
+    }
+
+    /// Returns whether the given render is already in the cache.
+    pub fn is_cached(&self, output_frames: usize, format: Format, options: RenderOptions) -> bool {
+        let path = self.config.cache_dir.join(render_key(
+            self.version.as_deref(),
+            output_frames,
+            format,
+            options,
+        ));
+        self.index.lock().contains(&path)
+    }
+
+    /// Waits for pending writes to the cache database to finish and flushes them to disk.
+    pub async fn flush(&self) -> Result<(), Error> {
+        let database = Arc::clone(&self.database);
+        tokio::task::spawn_blocking(move || database.lock().cache_flush())
+            .await
+            .map_err(|e| Error::DbQuery(e.to_string()))??;
+        Ok(())
+    }
+
+    /// Removes every rendered animation from the cache. The database is locked for the duration
+    /// of the purge, so that it can't be used to record or remove files in the meantime.
+    pub async fn purge(&self) -> Result<Purged, Error> {
+        let database = Arc::clone(&self.database);
+        let index = Arc::clone(&self.index);
+        let memory = Arc::clone(&self.memory);
+        let cache_dir = self.config.cache_dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let database = database.lock();
+            index.lock().clear();
+            memory.lock().clear();
+            purge(&cache_dir, &database)
+        })
+        .await
+        .map_err(|e| Error::DbQuery(e.to_string()))?
+    }
+
+    pub async fn request(
+        &self,
+        output_frames: usize,
+        format: Format,
+        options: RenderOptions,
+    ) -> Result<CachedFile, Error> {
         let (tx, rx) = oneshot::channel();
         self.requests
             .send(GifRequest {
-                speed,
+                output_frames,
+                format,
+                options,
                 responder: tx,
+                span: Span::current(),
             })
             .await
             .map_err(|_| Error::GifServiceOffline)?;
@@ -264,3 +873,172 @@ impl CacheServiceHandle {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::unix::fs::PermissionsExt,
+        path::{Path, PathBuf},
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    use tokio::task::JoinHandle;
+
+    use crate::{
+        animation_info::AnimationInfo,
+        common::{Error, Format, RenderOptions},
+        metrics::Metrics,
+        render_service::{RenderService, RenderServiceHandle},
+    };
+
+    use super::{CacheServiceConfig, CacheServiceHandle, CachedFile, GifService};
+
+    /// Creates an empty directory for a test to work in.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("smugdancer-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Starts a render service whose encoder takes half a second and records each of its runs in
+    /// `runs` in the test directory, and returns it along with a cache config for it.
+    fn spawn_render_service(dir: &Path) -> (RenderServiceHandle, CacheServiceConfig, Arc<Metrics>) {
+        let encoder = dir.join("encoder.sh");
+        std::fs::write(
+            &encoder,
+            format!(
+                "#!/bin/sh\nsleep 0.5\necho run >> '{}'\nprintf 'GIF89a;'\n",
+                dir.join("runs").display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&encoder, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let render_config = toml::from_str(&format!(
+            r#"
+                encoder = "{}"
+                encoder_flags = ["{{frame_indices}}"]
+                max_jobs = 2
+                retries = 0
+            "#,
+            encoder.display()
+        ))
+        .unwrap();
+        let cache_config = toml::from_str(&format!(
+            r#"
+                cache_dir = "{}"
+                database = "{}"
+                limit = 16777216
+                purge_limit = 8388608
+                purge_max_count = 8
+            "#,
+            dir.join("gif").display(),
+            dir.join("cache.db").display()
+        ))
+        .unwrap();
+        let info = AnimationInfo {
+            fps: 50.0,
+            wave_count: 12.0,
+            frame_count: 12,
+            max_output_frames: 12,
+            version: None,
+        };
+        let metrics = Arc::new(Metrics::default());
+        let render_service =
+            RenderService::spawn(render_config, info, Arc::clone(&metrics)).unwrap();
+        (render_service, cache_config, metrics)
+    }
+
+    /// Starts a cache service rendering with `spawn_render_service`.
+    fn spawn_services(dir: &Path) -> (CacheServiceHandle, Arc<Metrics>) {
+        let (render_service, cache_config, metrics) = spawn_render_service(dir);
+        let cache_service =
+            GifService::spawn(cache_config, None, render_service, Arc::clone(&metrics)).unwrap();
+        (cache_service, metrics)
+    }
+
+    fn spawn_requests(
+        cache_service: &CacheServiceHandle,
+        count: usize,
+    ) -> Vec<JoinHandle<Result<CachedFile, Error>>> {
+        (0..count)
+            .map(|_| {
+                let cache_service = cache_service.clone();
+                tokio::spawn(async move {
+                    cache_service
+                        .request(6, Format::Gif, RenderOptions::default())
+                        .await
+                })
+            })
+            .collect()
+    }
+
+    fn encoder_runs(dir: &Path) -> usize {
+        std::fs::read_to_string(dir.join("runs"))
+            .unwrap_or_default()
+            .lines()
+            .count()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_requests_share_one_render_and_write() {
+        let dir = test_dir("concurrent-requests");
+        let (cache_service, metrics) = spawn_services(&dir);
+
+        for request in spawn_requests(&cache_service, 16) {
+            let file = request.await.unwrap().unwrap();
+            assert_eq!(file.data, b"GIF89a;");
+        }
+        assert_eq!(encoder_runs(&dir), 1);
+        assert_eq!(metrics.cache_writes.load(Ordering::Relaxed), 1);
+
+        // Once it's cached, the file is served without rendering or writing it again.
+        for request in spawn_requests(&cache_service, 16) {
+            assert!(request.await.unwrap().unwrap().queue_position.is_none());
+        }
+        assert_eq!(encoder_runs(&dir), 1);
+        assert_eq!(metrics.cache_writes.load(Ordering::Relaxed), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn render_continues_when_first_client_goes_away() {
+        let dir = test_dir("first-client-goes-away");
+        let (cache_service, metrics) = spawn_services(&dir);
+
+        let mut requests = spawn_requests(&cache_service, 4);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        requests.remove(0).abort();
+        for request in requests {
+            assert_eq!(request.await.unwrap().unwrap().data, b"GIF89a;");
+        }
+        assert_eq!(encoder_runs(&dir), 1);
+        assert_eq!(metrics.cache_writes.load(Ordering::Relaxed), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn files_being_read_are_not_removed() {
+        let dir = test_dir("files-being-read");
+        let (render_service, cache_config, metrics) = spawn_render_service(&dir);
+        let service = GifService::new(cache_config, None, render_service, metrics).unwrap();
+        let path = service.config.cache_dir.join("pinned.gif");
+        std::fs::write(&path, b"GIF89a;").unwrap();
+        service.index.lock().insert(path.clone());
+        let filename = path.to_str().unwrap().to_owned();
+
+        let lock = service.file_lock(&path);
+        let reading = lock.read().await;
+        service.remove_files(vec![filename.clone()]).await;
+        assert!(path.exists());
+        assert!(service.index.lock().contains(&path));
+
+        drop(reading);
+        service.remove_files(vec![filename]).await;
+        assert!(!path.exists());
+        assert!(!service.index.lock().contains(&path));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
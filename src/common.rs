@@ -1,23 +1,181 @@
 use std::{fmt::Display, io, sync::Arc};
 
 use axum::{http::StatusCode, Json};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/// The file format of a rendered animation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Gif,
+    Webp,
+}
+
+impl Format {
+    /// Every format animations can be rendered to.
+    pub const ALL: [Self; 2] = [Self::Gif, Self::Webp];
+
+    /// Looks up the format with the given file extension.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.name() == extension)
+    }
+
+    /// Picks the format preferred by the client according to its `Accept` header. WebP is only
+    /// served to clients that rank it strictly higher than GIF; everyone else gets a GIF.
+    pub fn from_accept(accept: &str) -> Self {
+        let quality = |format: Self| {
+            // The most specific media range matching the format decides its quality, as per
+            // RFC 9110. Formats that aren't matched at all aren't acceptable.
+            let mut best = (0, 0.0);
+            for media_range in accept.split(',') {
+                let mut params = media_range.split(';');
+                let specificity = match params.next().unwrap_or_default().trim() {
+                    media_type if media_type == format.content_type() => 3,
+                    "image/*" => 2,
+                    "*/*" => 1,
+                    _ => continue,
+                };
+                if specificity > best.0 {
+                    let quality = params
+                        .find_map(|param| param.trim().strip_prefix("q="))
+                        .and_then(|quality| quality.parse().ok())
+                        .unwrap_or(1.0);
+                    best = (specificity, quality);
+                }
+            }
+            best.1
+        };
+        if quality(Self::Webp) > quality(Self::Gif) {
+            Self::Webp
+        } else {
+            Self::Gif
+        }
+    }
+
+    /// The name of the format, as understood by giffel's `--format` flag. This is also used as
+    /// the extension of cached files.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gif => "gif",
+            Self::Webp => "webp",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Gif => "image/gif",
+            Self::Webp => "image/webp",
+        }
+    }
+
+    /// Reads the width and height of an animation in this format from its header.
+    pub fn dimensions(self, data: &[u8]) -> Option<(u32, u32)> {
+        let u16_le = |at: usize| Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?));
+        let u24_le = |at: usize| {
+            let bytes = data.get(at..at + 3)?;
+            Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+        };
+        match self {
+            // The logical screen descriptor follows the signature.
+            Self::Gif => Some((u16_le(6)?.into(), u16_le(8)?.into())),
+            // Animated WebP files start with a VP8X chunk, which stores the canvas size minus one.
+            Self::Webp => {
+                if data.get(12..16)? != b"VP8X" {
+                    return None;
+                }
+                Some((u24_le(24)? + 1, u24_le(27)? + 1))
+            }
+        }
+    }
+
+    /// Checks that a file looks like a complete animation in this format. This doesn't decode
+    /// it; only the header and the end of the file are checked, which is enough to catch empty
+    /// and truncated files.
+    pub fn is_complete_file(self, data: &[u8]) -> bool {
+        match self {
+            // GIFs end with a trailer byte.
+            Self::Gif => {
+                (data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a"))
+                    && data.last() == Some(&0x3B)
+            }
+            // WebP files are RIFF containers, whose header stores the size of the rest of the
+            // file.
+            Self::Webp => {
+                data.len() >= 12
+                    && data.starts_with(b"RIFF")
+                    && &data[8..12] == b"WEBP"
+                    && u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize + 8
+                        == data.len()
+            }
+        }
+    }
+}
+
+/// How a client asked for an animation to be rendered. Anything that wasn't asked for is left up
+/// to the encoder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct RenderOptions {
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    /// The encoder quality, from 1 to 100.
+    pub quality: Option<u8>,
+    /// Whether the encoder should dither colors.
+    pub dither: Option<bool>,
+}
+
+/// Returns a string uniquely identifying the animation rendered with the given number of frames,
+/// format, and options, from the given version of the animation's frames. This is used as the name
+/// of the cached file, as well as the response's ETag.
+pub fn render_key(
+    version: Option<&str>,
+    output_frames: usize,
+    format: Format,
+    options: RenderOptions,
+) -> String {
+    let mut key = match version {
+        Some(version) => format!("{version}-{output_frames}"),
+        None => output_frames.to_string(),
+    };
+    if options.width.is_some() || options.height.is_some() {
+        let dimension = |d: Option<u16>| d.map(|d| d.to_string()).unwrap_or_default();
+        key += &format!(
+            "-{}x{}",
+            dimension(options.width),
+            dimension(options.height)
+        );
+    }
+    if let Some(quality) = options.quality {
+        key += &format!("-q{quality}");
+    }
+    match options.dither {
+        Some(true) => key += "-dither",
+        Some(false) => key += "-nodither",
+        None => (),
+    }
+    format!("{key}.{}", format.name())
+}
+
 #[derive(Serialize)]
 pub struct ErrorMessage {
+    /// A stable identifier of the kind of error (eg. `RATE_LIMITED`), for clients to act on.
+    pub code: &'static str,
+    /// A message describing the error, for displaying to people.
     pub error: String,
 }
 
 pub type ErrorResponse = (StatusCode, Json<ErrorMessage>);
 
-pub fn error_response<E>(status_code: StatusCode, error: E) -> ErrorResponse
+pub fn error_response<E>(status_code: StatusCode, code: &'static str, error: E) -> ErrorResponse
 where
     E: Display,
 {
     (
         status_code,
         Json(ErrorMessage {
+            code,
             error: error.to_string(),
         }),
     )
@@ -30,10 +188,24 @@ pub enum Error {
     #[error("yawn…")]
     SpeedTooSlow,
 
+    #[error("There are no previews available for this animation")]
+    PreviewUnavailable,
+
     #[error("GIF encoding process: {0}")]
     Encoder(io::Error),
     #[error("GIF encoder finished with a non-zero exit code")]
     EncoderExitCode,
+    #[error("GIF encoder was killed by signal {0}")]
+    EncoderKilled(i32),
+    #[error("GIF encoder did not output anything")]
+    EncoderNoOutput,
+    #[error("GIF encoder took too long and was stopped")]
+    EncoderTimeout,
+    #[error(
+        "Hat Kid is dancing as fast as she can! Too many GIFs are being rendered right now, \
+         please try again later."
+    )]
+    RenderQueueFull,
     #[error("Cache database: {0}")]
     CacheDb(#[from] rusqlite::Error),
     #[error("Database query: {0}")]
@@ -46,43 +218,78 @@ pub enum Error {
     GifServiceOffline,
     #[error("Internal encoding job failure (did not receive rendered GIF)")]
     EncodingJobExited,
+    #[error("Request was cancelled by the client")]
+    Cancelled,
     #[error("Invalid UTF-8")]
     InvalidUtf8,
-    #[error("System clock went backwards")]
-    ClockWentBackwards,
+    #[error("Render service is misconfigured: {0}")]
+    Misconfigured(String),
     #[error("Directory cannot be set up: {0}")]
     DirSetup(io::Error),
     #[error("Render failed: {0}")]
     RenderFailed(Arc<Error>),
-
-    #[error("Cache garbage collection I/O: {0}")]
-    CollectGarbage(io::Error),
+    #[error("Cannot purge cache: {0}")]
+    Purge(io::Error),
 }
 
 impl Error {
     pub fn status_code(&self) -> StatusCode {
         match self {
             Self::SpeedTooFast | Self::SpeedTooSlow => StatusCode::BAD_REQUEST,
+            Self::PreviewUnavailable => StatusCode::NOT_FOUND,
+            Self::RenderQueueFull => StatusCode::SERVICE_UNAVAILABLE,
             Self::Encoder(_)
             | Self::EncoderExitCode
+            | Self::EncoderKilled(_)
+            | Self::EncoderNoOutput
+            | Self::EncoderTimeout
             | Self::CacheDb(_)
             | Self::DbQuery(_)
             | Self::CannotReadGif(_)
             | Self::CannotWriteGif(_)
             | Self::GifServiceOffline
             | Self::EncodingJobExited
+            | Self::Cancelled
             | Self::InvalidUtf8
-            | Self::ClockWentBackwards
+            | Self::Misconfigured(_)
             | Self::DirSetup(_)
-            | Self::CollectGarbage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            | Self::Purge(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::RenderFailed(error) => error.status_code(),
         }
     }
 
+    /// Returns the error's code in JSON error responses.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::SpeedTooFast => "SPEED_TOO_FAST",
+            Self::SpeedTooSlow => "SPEED_TOO_SLOW",
+            Self::PreviewUnavailable => "PREVIEW_UNAVAILABLE",
+            Self::RenderQueueFull => "RENDER_QUEUE_FULL",
+            Self::Encoder(_)
+            | Self::EncoderExitCode
+            | Self::EncoderKilled(_)
+            | Self::EncoderNoOutput => "ENCODER_FAILED",
+            Self::EncoderTimeout => "ENCODER_TIMEOUT",
+            Self::CacheDb(_)
+            | Self::DbQuery(_)
+            | Self::CannotReadGif(_)
+            | Self::CannotWriteGif(_)
+            | Self::GifServiceOffline
+            | Self::EncodingJobExited
+            | Self::Cancelled
+            | Self::InvalidUtf8
+            | Self::Misconfigured(_)
+            | Self::DirSetup(_)
+            | Self::Purge(_) => "INTERNAL_ERROR",
+            Self::RenderFailed(error) => error.code(),
+        }
+    }
+
     pub fn to_response(&self) -> ErrorResponse {
         (
             self.status_code(),
             Json(ErrorMessage {
+                code: self.code(),
                 error: match self {
                     Self::RenderFailed(error) if error.status_code() == StatusCode::BAD_REQUEST => {
                         error.to_string()
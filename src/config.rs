@@ -1,12 +1,277 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
+
 use serde::Deserialize;
 
-use crate::{cache_service::CacheServiceConfig, render_service::RenderServiceConfig};
+use crate::{
+    cache_service::CacheServiceConfig, rate_limit::RateLimitConfig,
+    render_service::RenderServiceConfig,
+};
 
 pub const PATH: &str = "smugdancer.toml";
 
+/// The kind of value an environment variable holds.
+#[derive(Clone, Copy)]
+enum EnvValue {
+    String,
+    Integer,
+    Boolean,
+}
+
+/// Environment variables that override values from the config file, and the keys they override.
+/// They take precedence over the file, which takes precedence over the defaults. The cache
+/// variables only apply to the default animation.
+const ENV_OVERRIDES: [(&str, &[&str], EnvValue); 9] = [
+    (
+        "SMUGDANCER_BIND_ADDRESS",
+        &["server", "bind_address"],
+        EnvValue::String,
+    ),
+    ("SMUGDANCER_PORT", &["server", "port"], EnvValue::Integer),
+    ("SMUGDANCER_ROOT", &["server", "root"], EnvValue::String),
+    (
+        "SMUGDANCER_RATE_LIMITING",
+        &["server", "rate_limiting"],
+        EnvValue::Boolean,
+    ),
+    (
+        "SMUGDANCER_ADMIN_TOKEN",
+        &["server", "admin_token"],
+        EnvValue::String,
+    ),
+    (
+        "SMUGDANCER_CACHE_DIR",
+        &["cache_service", "cache_dir"],
+        EnvValue::String,
+    ),
+    (
+        "SMUGDANCER_CACHE_DATABASE",
+        &["cache_service", "database"],
+        EnvValue::String,
+    ),
+    (
+        "SMUGDANCER_CACHE_LIMIT",
+        &["cache_service", "limit"],
+        EnvValue::Integer,
+    ),
+    (
+        "SMUGDANCER_CACHE_PURGE_LIMIT",
+        &["cache_service", "purge_limit"],
+        EnvValue::Integer,
+    ),
+];
+
+/// Keys that take effect when the config is reloaded while the server is running. Keys of the
+/// cache services apply to the default animation as well as the ones under `animations`.
+const RELOADABLE_KEYS: [&str; 9] = [
+    "server.rate_limiting",
+    "server.rate_limit",
+    "cache_service.limit",
+    "cache_service.purge_limit",
+    "cache_service.max_files",
+    "cache_service.purge_to_files",
+    "cache_service.purge_max_count",
+    "cache_service.eviction",
+    "cache_service.ttl_seconds",
+];
+
+/// Reads the config file and applies the overrides from the environment. The raw TOML is
+/// returned alongside the config, so that it can be compared with the file when it's reloaded.
+pub fn load() -> Result<(toml::Value, Config), String> {
+    let file = std::fs::read_to_string(PATH).map_err(|e| format!("cannot read {PATH}: {e}"))?;
+    let mut raw: toml::Value =
+        toml::from_str(&file).map_err(|e| format!("cannot parse {PATH}: {e}"))?;
+    apply_env_overrides(&mut raw).map_err(|e| format!("invalid config override: {e}"))?;
+    let config = raw
+        .clone()
+        .try_into()
+        .map_err(|e| format!("invalid config in {PATH}: {e}"))?;
+    Ok((raw, config))
+}
+
+/// Returns the keys (eg. `server.port`) that differ between two versions of the config and
+/// aren't among `RELOADABLE_KEYS`, so they only take effect after a restart.
+pub fn changes_requiring_restart(old: &toml::Value, new: &toml::Value) -> Vec<String> {
+    let mut changed = vec![];
+    changed_keys("", Some(old), Some(new), &mut changed);
+    changed.retain(|key| {
+        // NOTE: Named animations' keys are compared the same way as the default animation's.
+        let key = match key.strip_prefix("animations.") {
+            Some(rest) => rest.split_once('.').map_or(rest, |(_, key)| key),
+            None => key,
+        };
+        !RELOADABLE_KEYS.iter().any(|reloadable| {
+            key.strip_prefix(reloadable)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+    });
+    changed
+}
+
+/// Collects the keys of the values that differ between `old` and `new`, descending into tables.
+fn changed_keys(
+    prefix: &str,
+    old: Option<&toml::Value>,
+    new: Option<&toml::Value>,
+    changed: &mut Vec<String>,
+) {
+    match (old, new) {
+        (Some(toml::Value::Table(old)), Some(toml::Value::Table(new))) => {
+            let keys: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                changed_keys(&path, old.get(key), new.get(key), changed);
+            }
+        }
+        (old, new) if old != new => changed.push(prefix.to_owned()),
+        _ => (),
+    }
+}
+
+/// Overrides values in the parsed config file with the ones set in `ENV_OVERRIDES`' environment
+/// variables.
+pub fn apply_env_overrides(config: &mut toml::Value) -> Result<(), String> {
+    for (name, key, kind) in ENV_OVERRIDES {
+        let raw = match std::env::var(name) {
+            Ok(raw) => raw,
+            Err(std::env::VarError::NotPresent) => continue,
+            Err(error) => return Err(format!("{name}: {error}")),
+        };
+        let value = match kind {
+            EnvValue::String => toml::Value::String(raw),
+            EnvValue::Integer => toml::Value::Integer(
+                raw.parse()
+                    .map_err(|_| format!("{name} must be a whole number, but it is {raw:?}"))?,
+            ),
+            EnvValue::Boolean => toml::Value::Boolean(
+                raw.parse()
+                    .map_err(|_| format!("{name} must be `true` or `false`, but it is {raw:?}"))?,
+            ),
+        };
+        let (last, tables) = key.split_last().expect("keys are never empty");
+        let mut table = &mut *config;
+        for &part in tables {
+            table = table
+                .as_table_mut()
+                .ok_or_else(|| format!("{name}: `{part}` in the config file is not a table"))?
+                .entry(part)
+                .or_insert_with(|| toml::Value::Table(Default::default()));
+        }
+        table
+            .as_table_mut()
+            .ok_or_else(|| {
+                format!(
+                    "{name}: `{}` in the config file is not a table",
+                    tables.join(".")
+                )
+            })?
+            .insert((*last).to_owned(), value);
+    }
+    Ok(())
+}
+
+/// Names additional animations can't have, as they're taken by the default animation (whose
+/// metrics and cache status are reported under `default`), or by routes of the server that would
+/// shadow the animation's.
+const RESERVED_ANIMATION_NAMES: [&str; 15] = [
+    "default",
+    "admin",
+    "api",
+    "favicon.ico",
+    "font.ttf",
+    "healthz",
+    "index.html",
+    "index.js",
+    "man",
+    "metrics",
+    "og.png",
+    "preview",
+    "pricing",
+    "render",
+    "style.css",
+];
+
 #[derive(Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
+    /// The default animation, served under `/:query`.
+    #[serde(flatten)]
+    pub default_animation: AnimationServicesConfig,
+    /// Additional animations, served under `/:animation/:query`. Each animation must use a cache
+    /// directory and database of its own, and can't have any of the `RESERVED_ANIMATION_NAMES`.
+    #[serde(default)]
+    pub animations: HashMap<String, AnimationServicesConfig>,
+}
+
+impl Config {
+    /// Checks the whole config for mistakes, so that they're reported when the server starts
+    /// rather than when the first request runs into them. All problems are reported at once, one
+    /// per line.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = vec![];
+        problems.extend(
+            self.server
+                .rate_limit
+                .validate()
+                .into_iter()
+                .map(|problem| format!("`server.rate_limit`: {problem}")),
+        );
+        for name in self.animations.keys() {
+            if RESERVED_ANIMATION_NAMES.contains(&name.as_str()) {
+                problems.push(format!(
+                    "animation `{name}`: the name is reserved, as it would clash with the default \
+                     animation or one of the server's routes"
+                ));
+            }
+        }
+        let animations = [("default", &self.default_animation)].into_iter().chain(
+            self.animations
+                .iter()
+                .map(|(name, config)| (name.as_str(), config)),
+        );
+        let mut cache_dirs = HashSet::new();
+        let mut databases = HashSet::new();
+        for (name, config) in animations {
+            let cache = &config.cache_service;
+            let mut animation_problems = config.animation.validate();
+            animation_problems.extend(config.render_service.validate());
+            animation_problems.extend(cache.validate());
+            if !cache_dirs.insert(&cache.cache_dir) {
+                animation_problems.push(format!(
+                    "the cache directory ({}) is already used by another animation",
+                    cache.cache_dir.display()
+                ));
+            }
+            if !databases.insert(&cache.database) {
+                animation_problems.push(format!(
+                    "the cache database ({}) is already used by another animation",
+                    cache.database.display()
+                ));
+            }
+            problems.extend(
+                animation_problems
+                    .into_iter()
+                    .map(|problem| format!("animation `{name}`: {problem}")),
+            );
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
+    }
+}
+
+/// An animation, along with the configuration of the services that render and cache it.
+#[derive(Deserialize)]
+pub struct AnimationServicesConfig {
     pub animation: AnimationConfig,
     pub render_service: RenderServiceConfig,
     pub cache_service: CacheServiceConfig,
@@ -17,25 +282,97 @@ pub struct AnimationConfig {
     /// The framerate at which the resulting GIF should be rendered. This value is substituted for
     /// the argument `{fps}` in the render command.
     ///
-    /// NOTE: 50 fps is a GIF limitation. See index.hbs.
+    /// Must be greater than 0 and at most 50, as GIFs can't be played back any faster. GIF frames
+    /// last a whole number of hundredths of a second, so it must also divide 100 evenly (eg. 50,
+    /// 25 or 20.) The same limits apply to every format, as all of them are rendered from the same
+    /// tempo calculations; WebP frame delays are in milliseconds, so they're exact at these rates.
     pub fps: f64,
-    /// The number of times Hat Kid waves her hands back and forth in the animation.
+    /// The number of times Hat Kid waves her hands back and forth in the animation. Must be
+    /// greater than 0.
     pub wave_count: f64,
     /// The way of obtaining the frame count.
     /// For giffel archives, `Command` should be used running `giffel stat <archive> frame-count`.
+    /// For a directory of frames, `Directory` counts the files in it.
     pub frame_count: FrameCountSource,
+    /// The version of the animation's frames. It's part of the names of cached files, so changing
+    /// it when the frames change keeps old renders from being served; they're removed from the
+    /// cache over time like any other unused file. May only contain ASCII letters, digits, `.`,
+    /// `_` and `-`.
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+impl AnimationConfig {
+    /// Checks the config for values the tempo calculations can't work with, returning a
+    /// description of each one.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        // NOTE: GIF frame delays are counted in hundredths of a second, and browsers play
+        // anything shorter than two of them much slower than intended.
+        if !(self.fps.is_finite() && self.fps > 0.0 && self.fps <= 50.0) {
+            problems.push(format!(
+                "`fps` must be greater than 0 and at most 50, but it is {}",
+                self.fps
+            ));
+        } else {
+            let delay = 100.0 / self.fps;
+            if (delay - delay.round()).abs() > 1e-9 {
+                problems.push(format!(
+                    "`fps` must divide 100 evenly (eg. 50, 25 or 20), as GIF frames last a whole \
+                     number of hundredths of a second; at {} fps, GIFs would play at {:.2} fps \
+                     and get out of sync with the tempo",
+                    self.fps,
+                    100.0 / delay.round()
+                ));
+            }
+        }
+        if !(self.wave_count.is_finite() && self.wave_count > 0.0) {
+            problems.push(format!(
+                "`wave_count` must be greater than 0, but it is {}",
+                self.wave_count
+            ));
+        }
+        if let Some(version) = &self.version {
+            let valid = !version.is_empty()
+                && version
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+            if !valid {
+                problems.push(format!(
+                    "`version` ({version:?}) may only contain ASCII letters, digits, `.`, `_` \
+                     and `-`"
+                ));
+            }
+        }
+        problems
+    }
 }
 
 /// Source for obtaining the number of frames in an animation.
 #[derive(Deserialize)]
 #[serde(untagged)]
 pub enum FrameCountSource {
-    Hardcoded { hardcoded: usize },
-    Command { command: String, flags: Vec<String> },
+    Hardcoded {
+        hardcoded: usize,
+    },
+    Command {
+        command: String,
+        flags: Vec<String>,
+    },
+    /// Counts the files in `path` whose extension is `extension` (eg. `png`.)
+    Directory {
+        path: PathBuf,
+        extension: String,
+    },
 }
 
 #[derive(Deserialize)]
 pub struct ServerConfig {
+    /// The address of the interface smugdancer should listen on. Both IPv4 and IPv6 addresses are
+    /// accepted. Defaults to `0.0.0.0`, which listens on all IPv4 interfaces; `::` listens on all
+    /// IPv4 and IPv6 interfaces.
+    #[serde(default = "default_bind_address")]
+    pub bind_address: IpAddr,
     /// The port under which smugdancer should serve.
     pub port: u16,
     /// The root URL that's shown on the documentation website.
@@ -45,13 +382,161 @@ pub struct ServerConfig {
     /// practical. On production servers this should be **always** enabled.
     #[serde(default = "enabled")]
     pub rate_limiting: bool,
+    /// How many requests each IP is allowed to make.
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
     /// Set to `true` if the server is behind a reverse proxy like nginx.
     /// This makes it use the X-Forwarded-For header for rate limiting instead of the connection's
     /// IP address.
     #[serde(default)]
     pub reverse_proxy: bool,
+    /// Origins allowed to fetch rendered animations from other websites, eg.
+    /// `https://example.com`. `*` allows any origin. By default, only same-origin requests are
+    /// allowed.
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+    /// How long to wait for queued renders to finish when shutting down, in seconds.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// The largest width and height clients may request animations to be rendered at, in pixels.
+    /// Custom sizes are not allowed if this is not set.
+    #[serde(default)]
+    pub max_render_size: Option<u16>,
+    /// Whether requests for tempos that get quantized are redirected to the URL of the tempo they
+    /// were quantized to, so that all of them share one cached response.
+    #[serde(default)]
+    pub canonical_redirect: CanonicalRedirect,
+    /// The token that has to be sent as `Authorization: Bearer <token>` to use the admin
+    /// endpoints under `/admin`. They're disabled if this is not set.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Messages to send to clients instead of the built-in ones for some errors.
+    #[serde(default)]
+    pub messages: ErrorMessages,
+}
+
+/// Replacements for the messages of errors clients run into during normal use, most of which
+/// talk about Hat Kid. The built-in message is used for any that aren't set.
+#[derive(Default, Deserialize)]
+pub struct ErrorMessages {
+    /// Sent when a client makes requests too often.
+    pub rate_limited: Option<String>,
+    /// Sent when a client has too many requests waiting for an animation at once.
+    pub too_many_concurrent_requests: Option<String>,
+    /// Sent when too many renders are queued to start another one.
+    pub render_queue_full: Option<String>,
+    /// Sent when the requested tempo is too fast to be rendered. Note that the built-in message
+    /// also tells the client which tempos can be rendered.
+    pub speed_too_fast: Option<String>,
+    /// Sent when the requested tempo is too slow to be rendered.
+    pub speed_too_slow: Option<String>,
+}
+
+impl ErrorMessages {
+    /// Returns the message to send with errors of the given code, if one is configured.
+    pub fn get(&self, code: &str) -> Option<&str> {
+        let message = match code {
+            "RATE_LIMITED" => &self.rate_limited,
+            "TOO_MANY_CONCURRENT_REQUESTS" => &self.too_many_concurrent_requests,
+            "RENDER_QUEUE_FULL" => &self.render_queue_full,
+            "SPEED_TOO_FAST" => &self.speed_too_fast,
+            "SPEED_TOO_SLOW" => &self.speed_too_slow,
+            _ => &None,
+        };
+        message.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CanonicalRedirect {
+    /// Serve quantized tempos under the requested URL.
+    #[default]
+    Disabled,
+    /// Redirect with 308 Permanent Redirect.
+    Permanent,
+    /// Redirect with 307 Temporary Redirect.
+    Temporary,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_bind_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
 }
 
 fn enabled() -> bool {
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    fn example_config() -> Config {
+        toml::from_str(include_str!("../smugdancer.toml")).unwrap()
+    }
+
+    /// Returns the problems with the example config after applying `modify` to it that contain
+    /// `topic`. The example's frames aren't around during tests, so other parts of it are
+    /// expected to have problems of their own.
+    fn problems(topic: &str, modify: impl FnOnce(&mut Config)) -> Vec<String> {
+        let mut config = example_config();
+        modify(&mut config);
+        config
+            .validate()
+            .err()
+            .unwrap_or_default()
+            .lines()
+            .filter(|problem| problem.contains(topic))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    fn rate_limit_problems(modify: impl FnOnce(&mut Config)) -> Vec<String> {
+        problems("`server.rate_limit`", modify)
+    }
+
+    #[test]
+    fn example_rate_limits_are_valid() {
+        assert!(rate_limit_problems(|_| ()).is_empty());
+    }
+
+    #[test]
+    fn unusable_rate_limits_are_rejected() {
+        let problems = rate_limit_problems(|config| config.server.rate_limit.rate = 0.0);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("`rate`"), "{problems:?}");
+
+        let problems = rate_limit_problems(|config| config.server.rate_limit.burst = 0.5);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("`burst`"), "{problems:?}");
+
+        let problems = rate_limit_problems(|config| {
+            config.server.rate_limit.rate = -1.0;
+            config.server.rate_limit.max_concurrent = Some(0);
+        });
+        assert_eq!(problems.len(), 2, "{problems:?}");
+    }
+
+    #[test]
+    fn reserved_animation_names_are_rejected() {
+        for name in ["default", "api", "preview", "metrics"] {
+            let problems = problems("reserved", |config| {
+                config
+                    .animations
+                    .insert(name.to_owned(), example_config().default_animation);
+            });
+            assert_eq!(problems.len(), 1, "{name}: {problems:?}");
+            assert!(problems[0].starts_with(&format!("animation `{name}`")));
+        }
+        let problems = problems("reserved", |config| {
+            config
+                .animations
+                .insert("example".to_owned(), example_config().default_animation);
+        });
+        assert!(problems.is_empty(), "{problems:?}");
+    }
+}
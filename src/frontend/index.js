@@ -1,4 +1,5 @@
 const minimumBpm = Number.parseFloat("{{{minimum_bpm}}}")
+const maximumBpm = Number.parseFloat("{{{maximum_bpm}}}")
 
 const bpmInput = document.getElementById("bpm")
 const prettyPlease = document.getElementById("pretty-please")
@@ -146,7 +147,7 @@ document.addEventListener("pointerlockchange", event => {
 document.addEventListener("mousemove", event => {
     if (draggingBpm) {
         draggedBpm += event.movementX / 10
-        draggedBpm = Math.min(Math.max(draggedBpm, minimumBpm), 18000)
+        draggedBpm = Math.min(Math.max(draggedBpm, minimumBpm), maximumBpm)
         bpmInput.value = Math.round(draggedBpm).toString()
     }
 })
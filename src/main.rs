@@ -4,39 +4,59 @@ mod animation_info;
 mod cache_service;
 mod common;
 mod config;
+mod memory_cache;
+mod metrics;
+mod rate_limit;
 mod render_service;
 
 use std::{
-    net::{IpAddr, SocketAddr},
+    collections::HashMap,
+    error::Error as _,
+    future::Future,
+    net::{AddrParseError, IpAddr, Ipv6Addr, SocketAddr, TcpListener},
+    path::Path,
+    pin::Pin,
     str::FromStr,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
 };
 
 use axum::{
-    extract::{ConnectInfo, Path as UrlPath},
-    http::{HeaderMap, StatusCode},
+    body::StreamBody,
+    extract::{rejection::JsonRejection, ConnectInfo, Path as UrlPath, Query},
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware::{self, Next},
     response::{Html, IntoResponse, Redirect, Response},
-    routing::get,
-    Extension, Router,
+    routing::{get, post},
+    Extension, Json, Router,
 };
-use cache_service::CacheServiceHandle;
-use common::ErrorResponse;
-use config::ServerConfig;
-use dashmap::DashSet;
+use cache_service::{CacheServiceHandle, CacheStats, CachedFile, Purged};
+use common::{render_key, Error, ErrorResponse, Format, RenderOptions};
+use config::{CanonicalRedirect, ServerConfig};
+use futures_util::Stream;
 use handlebars::Handlebars;
-use render_service::RenderService;
-use serde::Serialize;
-use tracing::{debug, info};
+use metrics::Metrics;
+use nanorand::{Rng, WyRand};
+use rate_limit::{InFlight, RateLimiter};
+use render_service::{RenderService, RenderServiceHandle, RenderStream};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use thiserror::Error;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use crate::{
-    animation_info::AnimationInfo, cache_service::GifService, common::error_response,
-    config::Config,
+    animation_info::{AnimationInfo, AnimationInfoError},
+    cache_service::GifService,
+    common::error_response,
+    config::{AnimationServicesConfig, Config},
 };
 
 #[derive(Serialize)]
 struct TemplateDataConfig {
     root: String,
     minimum_bpm: f64,
+    maximum_bpm: f64,
 }
 
 #[derive(Serialize)]
@@ -102,18 +122,181 @@ fn render_index(config: TemplateDataConfig) -> Pages {
     }
 }
 
+/// A reason why an animation couldn't be started.
+#[derive(Debug, Error)]
+enum SpawnError {
+    #[error(transparent)]
+    AnimationInfo(#[from] AnimationInfoError),
+    #[error(transparent)]
+    Service(#[from] Error),
+}
+
+/// An animation and the services that render it.
+struct Animation {
+    /// The info about the animation.
+    info: AnimationInfo,
+    /// The GIF service.
+    gif_service: CacheServiceHandle,
+    /// The render service. Requests should go through the GIF service instead, this is only kept
+    /// around for health checks.
+    render_service: RenderServiceHandle,
+    /// Metrics collected by the services.
+    metrics: Arc<Metrics>,
+}
+
+impl Animation {
+    fn spawn(name: &str, config: AnimationServicesConfig) -> Result<Self, SpawnError> {
+        let info =
+            AnimationInfo::from_config(&config.animation, config.render_service.max_output_frames)?;
+        debug!(name, ?info, "resolved animation info");
+        debug!(
+            name,
+            minimum_bpm = info.minimum_bpm(),
+            "calculated minimum tempo (given {} waves at {} fps, rendered in at most {} frames)",
+            info.wave_count,
+            info.fps,
+            info.max_output_frames
+        );
+
+        let warm_bpms: Vec<_> = config
+            .cache_service
+            .warm_bpms
+            .iter()
+            .copied()
+            .chain(
+                config
+                    .cache_service
+                    .warm_bpm_range
+                    .into_iter()
+                    .flat_map(|(start, end)| {
+                        info.supported_bpms()
+                            .filter(move |&bpm| bpm >= start && bpm <= end)
+                    }),
+            )
+            .collect();
+        let warm_formats = config.cache_service.warm_formats.clone();
+
+        let metrics = Arc::new(Metrics::default());
+        let render_service =
+            RenderService::spawn(config.render_service, info.clone(), Arc::clone(&metrics))?;
+        let gif_service = GifService::spawn(
+            config.cache_service,
+            info.version.clone(),
+            render_service.clone(),
+            Arc::clone(&metrics),
+        )?;
+        let animation = Self {
+            info,
+            gif_service,
+            render_service,
+            metrics,
+        };
+        animation.warm_cache(name, &warm_bpms, warm_formats);
+        Ok(animation)
+    }
+
+    /// Renders the given tempos into the cache in the background.
+    fn warm_cache(&self, name: &str, bpms: &[f64], formats: Vec<Format>) {
+        let mut frame_counts = vec![];
+        for &bpm in bpms {
+            let output_frames = match self.info.clamp_bpm(bpm) {
+                Ok(bpm) => self
+                    .render_service
+                    .output_frame_count(bpm / self.info.natural_bpm())
+                    .map_err(|error| error.to_string()),
+                Err(error) => Err(error.to_string()),
+            };
+            match output_frames {
+                Ok(output_frames) => frame_counts.push(output_frames),
+                Err(error) => warn!(name, bpm, "cannot warm the cache with this tempo: {error}"),
+            }
+        }
+        frame_counts.sort_unstable();
+        frame_counts.dedup();
+        if frame_counts.is_empty() {
+            return;
+        }
+
+        let name = name.to_owned();
+        let gif_service = self.gif_service.clone();
+        tokio::spawn(async move {
+            let total = frame_counts.len() * formats.len();
+            info!(name, total, "warming the cache");
+            let mut done = 0;
+            // NOTE: Renders are requested one at a time, so that warming the cache never takes
+            // up more than one job slot or fills up the render queue for actual clients.
+            for &format in &formats {
+                for &output_frames in &frame_counts {
+                    let result = gif_service
+                        .request(output_frames, format, RenderOptions::default())
+                        .await;
+                    done += 1;
+                    match result {
+                        Ok(_) => info!(name, output_frames, ?format, done, total, "warmed"),
+                        Err(error) => warn!(
+                            name,
+                            output_frames,
+                            ?format,
+                            "cannot warm the cache: {error}"
+                        ),
+                    }
+                }
+            }
+            info!(name, "finished warming the cache");
+        });
+    }
+}
+
 struct State {
     /// The config file.
     config: ServerConfig,
-    /// The info about the animation.
-    animation_info: AnimationInfo,
+    /// The config file as it was read when the server started, for telling which changes to it
+    /// need a restart when it's reloaded.
+    raw_config: toml::Value,
     /// The index containing documentation.
     pages: Pages,
-    /// The GIF service.
-    gif_service: CacheServiceHandle,
-    /// A map of IP addresses that are currently waiting in the render queue. These IPs will be
-    /// rate limited so as not to kill the server with requests.
-    waiting_clients: DashSet<IpAddr>,
+    /// The animation served under `/:query`.
+    default_animation: Animation,
+    /// Additional animations, served under `/:animation/:query`.
+    animations: HashMap<String, Animation>,
+    /// Limits how often each IP can request animations, so as not to kill the server with
+    /// requests.
+    rate_limiter: RateLimiter,
+}
+
+impl State {
+    /// Looks up a named animation.
+    fn animation(&self, name: &str) -> Result<&Animation, ErrorResponse> {
+        self.animations.get(name).ok_or_else(|| {
+            error_response(
+                StatusCode::NOT_FOUND,
+                "UNKNOWN_ANIMATION",
+                format!("There is no animation called {name:?}"),
+            )
+        })
+    }
+
+    /// Replaces the message of an error response with the one configured for its code, if any.
+    fn customize(&self, (status_code, Json(mut message)): ErrorResponse) -> ErrorResponse {
+        if let Some(custom) = self.config.messages.get(message.code) {
+            message.error = custom.to_owned();
+        }
+        (status_code, Json(message))
+    }
+
+    /// Turns an error into a response, with the configured message if there is one.
+    fn error_response(&self, error: &Error) -> ErrorResponse {
+        self.customize(error.to_response())
+    }
+
+    /// Iterates over all animations, including the default one.
+    fn all_animations(&self) -> impl Iterator<Item = (&str, &Animation)> {
+        std::iter::once(("default", &self.default_animation)).chain(
+            self.animations
+                .iter()
+                .map(|(name, animation)| (name.as_str(), animation)),
+        )
+    }
 }
 
 async fn index(Extension(state): Extension<Arc<State>>) -> Html<String> {
@@ -140,67 +323,948 @@ async fn font() -> impl IntoResponse {
     ([("content-type", "font/ttf")], FONT)
 }
 
-async fn render_animation(
+async fn favicon() -> impl IntoResponse {
+    const FAVICON: &[u8] = include_bytes!("frontend/favicon.ico");
+    ([("content-type", "image/x-icon")], FAVICON)
+}
+
+async fn og_image() -> impl IntoResponse {
+    const OG_IMAGE: &[u8] = include_bytes!("frontend/og.png");
+    ([("content-type", "image/png")], OG_IMAGE)
+}
+
+#[derive(Deserialize)]
+struct HealthQuery {
+    verbose: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_depth: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_writable: Option<bool>,
+}
+
+async fn is_cache_writable(cache_dir: &Path) -> bool {
+    let probe = cache_dir.join(".healthz");
+    let writable = tokio::fs::write(&probe, b"").await.is_ok();
+    let _ = tokio::fs::remove_file(&probe).await;
+    writable
+}
+
+async fn healthz(
+    Extension(state): Extension<Arc<State>>,
+    Query(query): Query<HealthQuery>,
+) -> (StatusCode, Json<Health>) {
+    let mut problems = vec![];
+    for (name, animation) in state.all_animations() {
+        if !animation.render_service.is_running() {
+            problems.push(format!("{name}: render service is not running"));
+        }
+        if !animation.gif_service.is_running() {
+            problems.push(format!("{name}: cache service is not running"));
+        }
+        for encoder in animation.render_service.unavailable_encoders() {
+            problems.push(format!(
+                "{name}: encoder {} does not exist or is not executable",
+                encoder.display()
+            ));
+        }
+        if let Err(problem) = animation.render_service.check_frames_accessible().await {
+            problems.push(format!("{name}: {problem}"));
+        }
+        if let Err(error) = tokio::fs::metadata(animation.gif_service.cache_dir()).await {
+            problems.push(format!(
+                "{name}: cache directory is not accessible: {error}"
+            ));
+        }
+    }
+
+    let (status_code, status) = if problems.is_empty() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    let verbose = query.verbose.is_some();
+    let health = Health {
+        status,
+        problems,
+        queue_depth: verbose.then(|| {
+            state
+                .all_animations()
+                .map(|(_, animation)| animation.render_service.queue_depth())
+                .sum()
+        }),
+        cache_writable: match verbose {
+            true => {
+                let mut writable = true;
+                for (_, animation) in state.all_animations() {
+                    writable &= is_cache_writable(animation.gif_service.cache_dir()).await;
+                }
+                Some(writable)
+            }
+            false => None,
+        },
+    };
+    (status_code, Json(health))
+}
+
+#[derive(Deserialize)]
+struct BpmsQuery {
+    /// The animation to list tempos for. Defaults to the default animation.
+    animation: Option<String>,
+    /// The maximum number of tempos to list.
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct AnimationQuery {
+    /// The animation to describe. Defaults to the default animation.
+    animation: Option<String>,
+}
+
+/// The properties of an animation that decide which tempos it can be rendered at.
+#[derive(Serialize)]
+struct AnimationMetadata {
+    minimum_bpm: f64,
+    maximum_bpm: f64,
+    wave_count: f64,
+    fps: f64,
+    frame_count: usize,
+    max_output_frames: usize,
+}
+
+impl From<&AnimationInfo> for AnimationMetadata {
+    fn from(info: &AnimationInfo) -> Self {
+        Self {
+            minimum_bpm: info.minimum_bpm(),
+            maximum_bpm: info.maximum_bpm(),
+            wave_count: info.wave_count,
+            fps: info.fps,
+            frame_count: info.frame_count,
+            max_output_frames: info.max_output_frames,
+        }
+    }
+}
+
+/// The tempos supported by an animation. These are `wave_count * fps * 60 / n` for every whole
+/// number `n` between 2 and `max_output_frames`.
+#[derive(Serialize)]
+struct Bpms {
+    #[serde(flatten)]
+    animation: AnimationMetadata,
+    /// The supported tempos from slowest to fastest, up to the limit.
+    supported_bpms: Vec<f64>,
+    /// Whether `supported_bpms` was cut short by the limit.
+    truncated: bool,
+}
+
+/// Exports render metrics in the Prometheus text format.
+async fn metrics(Extension(state): Extension<Arc<State>>) -> impl IntoResponse {
+    let animations: Vec<_> = state
+        .all_animations()
+        .map(|(name, animation)| (name, &*animation.metrics))
+        .collect();
+    let metrics = metrics::export(&animations);
+    ([("Content-Type", "text/plain; version=0.0.4")], metrics)
+}
+
+#[derive(Deserialize)]
+struct CacheQuery {
+    animation: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CacheStatus {
+    #[serde(flatten)]
+    stats: CacheStats,
+    /// Requests served from the cache since the server started.
+    hits: u64,
+    /// Requests that needed a render since the server started.
+    misses: u64,
+    /// The fraction of requests served from the cache, or `None` if there were none yet.
+    hit_ratio: Option<f64>,
+}
+
+/// Reports how full the cache is and how well it's doing, for dashboards and debugging eviction.
+async fn cache_status(
+    Extension(state): Extension<Arc<State>>,
+    Query(query): Query<CacheQuery>,
+) -> Result<Json<CacheStatus>, ErrorResponse> {
+    let animation = match &query.animation {
+        Some(name) => state.animation(name)?,
+        None => &state.default_animation,
+    };
+    let stats = animation
+        .gif_service
+        .stats()
+        .await
+        .map_err(|e| e.to_response())?;
+    let hits = animation.metrics.cache_hits.load(Ordering::Relaxed);
+    let misses = animation.metrics.cache_misses.load(Ordering::Relaxed);
+    let requests = hits + misses;
+    Ok(Json(CacheStatus {
+        stats,
+        hits,
+        misses,
+        hit_ratio: (requests > 0).then(|| hits as f64 / requests as f64),
+    }))
+}
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    animation: Option<String>,
+}
+
+/// Removes the cached animations of one animation, or all of them if none is given.
+async fn purge(
     Extension(state): Extension<Arc<State>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
-    UrlPath(query): UrlPath<String>,
-) -> Result<Response, ErrorResponse> {
-    let query = query.strip_suffix(".gif").unwrap_or(&query);
-    let unquantized_bpm: f64 = query.parse().map_err(|e| {
+    Query(query): Query<PurgeQuery>,
+) -> Result<Json<HashMap<String, Purged>>, ErrorResponse> {
+    check_admin_token(&state, &headers)?;
+
+    let animations: Vec<_> = match &query.animation {
+        Some(name) => vec![(name.as_str(), state.animation(name)?)],
+        None => state.all_animations().collect(),
+    };
+    let mut purged = HashMap::new();
+    for (name, animation) in animations {
+        let result = animation
+            .gif_service
+            .purge()
+            .await
+            .map_err(|e| e.to_response())?;
+        info!(
+            animation = name,
+            files = result.files,
+            bytes = result.bytes,
+            "purged cache"
+        );
+        purged.insert(name.to_owned(), result);
+    }
+    Ok(Json(purged))
+}
+
+/// Checks that the request carries the admin token.
+fn check_admin_token(state: &State, headers: &HeaderMap) -> Result<(), ErrorResponse> {
+    let admin_token = state.config.admin_token.as_ref().ok_or_else(|| {
+        error_response(
+            StatusCode::NOT_FOUND,
+            "ADMIN_DISABLED",
+            "Admin endpoints are disabled",
+        )
+    })?;
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // NOTE: The token is compared in constant time, so that it can't be guessed byte by byte
+    // from how long the comparison takes.
+    let matches = token.is_some_and(|token| {
+        token.len() == admin_token.len()
+            && token
+                .bytes()
+                .zip(admin_token.bytes())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    });
+    if matches {
+        Ok(())
+    } else {
+        Err(error_response(
+            StatusCode::UNAUTHORIZED,
+            "UNAUTHORIZED",
+            "Missing or invalid admin token",
+        ))
+    }
+}
+
+async fn animation_metadata(
+    Extension(state): Extension<Arc<State>>,
+    Query(query): Query<AnimationQuery>,
+) -> Result<Json<AnimationMetadata>, ErrorResponse> {
+    let animation = match &query.animation {
+        Some(name) => state.animation(name)?,
+        None => &state.default_animation,
+    };
+    Ok(Json(AnimationMetadata::from(&animation.info)))
+}
+
+async fn bpms(
+    Extension(state): Extension<Arc<State>>,
+    Query(query): Query<BpmsQuery>,
+) -> Result<Json<Bpms>, ErrorResponse> {
+    const DEFAULT_LIMIT: usize = 1000;
+
+    let animation = match &query.animation {
+        Some(name) => state.animation(name)?,
+        None => &state.default_animation,
+    };
+    let info = &animation.info;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    let supported_bpms: Vec<_> = info.supported_bpms().take(limit).collect();
+    Ok(Json(Bpms {
+        animation: AnimationMetadata::from(info),
+        truncated: supported_bpms.len() < info.max_output_frames.saturating_sub(1),
+        supported_bpms,
+    }))
+}
+
+/// Parses a render query of the form `bpm[.extension]`. If there's no extension, no format is
+/// returned and it's up to the caller to pick one.
+fn parse_query(query: &str) -> Result<(f64, Option<Format>), ErrorResponse> {
+    let (bpm, extension) = split_extension(query);
+    let format = extension
+        .map(|extension| {
+            Format::from_extension(extension)
+                .ok_or_else(|| unsupported_extension(extension, &Format::ALL.map(Format::name)))
+        })
+        .transpose()?;
+    Ok((parse_bpm(bpm)?, format))
+}
+
+/// Splits the extension off the end of a request path, eg. `128.gif` into `128` and `gif`. The
+/// fractional part of a tempo such as `127.5` is not mistaken for an extension.
+fn split_extension(query: &str) -> (&str, Option<&str>) {
+    match query.rsplit_once('.') {
+        Some((stem, extension)) if extension.starts_with(|c: char| !c.is_ascii_digit()) => {
+            (stem, Some(extension))
+        }
+        _ => (query, None),
+    }
+}
+
+fn unsupported_extension(extension: &str, supported: &[&str]) -> ErrorResponse {
+    let supported: Vec<_> = supported
+        .iter()
+        .map(|extension| format!(".{extension}"))
+        .collect();
+    error_response(
+        StatusCode::BAD_REQUEST,
+        "UNSUPPORTED_FORMAT",
+        format!(
+            "`.{extension}` is not a supported format. Use one of: {}.",
+            supported.join(", ")
+        ),
+    )
+}
+
+/// Parses a tempo written as a plain decimal number, such as `128` or `-127.5`.
+///
+/// `f64`'s own parser is much more lenient and would also take `inf`, `NaN` and exponents, none of
+/// which make sense in a URL. Anything else, such as a second extension, is rejected too.
+fn parse_bpm(bpm: &str) -> Result<f64, ErrorResponse> {
+    let digits = bpm.strip_prefix(['+', '-']).unwrap_or(bpm);
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let is_decimal = !(whole.is_empty() && fraction.is_empty())
+        && whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit());
+    is_decimal
+        .then(|| bpm.parse::<f64>().ok())
+        .flatten()
+        .filter(|bpm| bpm.is_finite())
+        .ok_or_else(|| {
+            error_response(
+                StatusCode::BAD_REQUEST,
+                "BAD_BPM",
+                format!(
+                    "Cannot parse BPM value `{bpm}`. It must be a decimal number, such as 128."
+                ),
+            )
+        })
+}
+
+/// A requested tempo, along with the supported tempo it was quantized to.
+#[derive(Clone, Copy)]
+struct Tempo {
+    /// The tempo the client asked for.
+    requested_bpm: f64,
+    /// The tempo the animation is rendered at.
+    bpm: f64,
+    /// The number of frames the animation has at that tempo.
+    output_frames: usize,
+}
+
+/// Quantizes the requested tempo and works out how many frames it renders to, making sure the
+/// animation can actually be rendered at it.
+fn quantize_bpm(
+    state: &State,
+    animation: &Animation,
+    requested_bpm: f64,
+) -> Result<Tempo, ErrorResponse> {
+    // NOTE: This is checked here rather than left to the render service, so that requests which
+    // can never succeed don't take up a spot in the render queue.
+    let bpm = animation.info.clamp_bpm(requested_bpm).map_err(|error| {
+        state.customize(error_response(StatusCode::BAD_REQUEST, error.code(), error))
+    })?;
+    let output_frames = animation
+        .render_service
+        .output_frame_count(bpm / animation.info.natural_bpm())
+        .map_err(|e| state.error_response(&e))?;
+    Ok(Tempo {
+        requested_bpm,
+        bpm,
+        output_frames,
+    })
+}
+
+#[derive(Deserialize)]
+struct OptionsQuery {
+    /// The requested width.
+    w: Option<u16>,
+    /// The requested height.
+    h: Option<u16>,
+    quality: Option<u8>,
+    dither: Option<bool>,
+}
+
+impl From<OptionsQuery> for RenderOptions {
+    fn from(query: OptionsQuery) -> Self {
+        Self {
+            width: query.w,
+            height: query.h,
+            quality: query.quality,
+            dither: query.dither,
+        }
+    }
+}
+
+/// Checks the requested options against the configured limits.
+fn requested_options(
+    config: &ServerConfig,
+    options: RenderOptions,
+) -> Result<RenderOptions, ErrorResponse> {
+    if matches!(options.quality, Some(quality) if !(1..=100).contains(&quality)) {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "BAD_QUALITY",
+            "Quality must be between 1 and 100.",
+        ));
+    }
+    if options.width.is_none() && options.height.is_none() {
+        return Ok(options);
+    }
+    let max_size = config.max_render_size.ok_or_else(|| {
         error_response(
             StatusCode::BAD_REQUEST,
-            format!("Cannot parse BPM value: {e}"),
+            "CUSTOM_SIZE_DISABLED",
+            "This server does not render animations at custom sizes.",
         )
     })?;
+    let in_range = |dimension: Option<u16>| !matches!(dimension, Some(d) if d == 0 || d > max_size);
+    if !in_range(options.width) || !in_range(options.height) {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "BAD_SIZE",
+            format!("Width and height must be between 1 and {max_size} pixels."),
+        ));
+    }
+    Ok(options)
+}
 
-    let ip = if state.config.reverse_proxy {
-        headers
-            .get("x-forwarded-for")
-            .and_then(|val| {
-                IpAddr::from_str(
-                    val.to_str()
-                        .expect("cannot parse X-Forwarded-For IP address"),
-                )
-                .ok()
-            })
-            .unwrap_or(addr.ip())
-    } else {
-        addr.ip()
+#[derive(Deserialize)]
+struct QueueQuery {
+    /// The requested tempo, in the same form as in the render URL (eg. `128` or `128.webp`.)
+    bpm: String,
+    animation: Option<String>,
+    w: Option<u16>,
+    h: Option<u16>,
+    quality: Option<u8>,
+    dither: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct QueueStatus {
+    /// The number of distinct renders that are queued or in progress.
+    queue_depth: usize,
+    /// The number of distinct renders that can be queued before new ones are turned away.
+    queue_capacity: usize,
+    /// Whether the requested tempo is being rendered right now.
+    rendering: bool,
+    /// The number of clients waiting for the requested tempo.
+    waiting_clients: usize,
+}
+
+/// Reports the status of the render queue, so that clients waiting for a render can poll it.
+async fn queue_status(
+    Extension(state): Extension<Arc<State>>,
+    Query(query): Query<QueueQuery>,
+) -> Result<Json<QueueStatus>, ErrorResponse> {
+    let animation = match &query.animation {
+        Some(name) => state.animation(name)?,
+        None => &state.default_animation,
+    };
+    let (unquantized_bpm, format) = parse_query(&query.bpm)?;
+    let format = format.unwrap_or(Format::Gif);
+    let options = RenderOptions {
+        width: query.w,
+        height: query.h,
+        quality: query.quality,
+        dither: query.dither,
     };
+    let options = requested_options(&state.config, options)?;
+    let tempo = quantize_bpm(&state, animation, unquantized_bpm)?;
 
-    if !state.config.rate_limiting || state.waiting_clients.insert(ip) {
-        // WARNING: DO NOT USE THE `?` OPERATOR UNTIL THE CLIENT IS REMOVED FROM THE WAIT LIST!!!
-        let bpm = state
-            .animation_info
-            .quantize_bpm_to_nearest_supported(unquantized_bpm);
-        debug!(
-            "serving {bpm} bpm (quantized from {unquantized_bpm} bpm) to {}",
-            ip
-        );
+    let waiting_clients =
+        animation
+            .render_service
+            .waiting_clients(tempo.output_frames, format, options);
+    Ok(Json(QueueStatus {
+        queue_depth: animation.render_service.queue_depth(),
+        queue_capacity: animation.render_service.queue_capacity(),
+        rendering: waiting_clients.is_some(),
+        waiting_clients: waiting_clients.unwrap_or(0),
+    }))
+}
 
-        let speed = bpm / state.animation_info.minimum_bpm();
-        let result = state
+async fn render_default_animation(
+    Extension(state): Extension<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    UrlPath(query): UrlPath<String>,
+    Query(options): Query<OptionsQuery>,
+) -> Result<Response, ErrorResponse> {
+    let options = requested_options(&state.config, options.into())?;
+    render_animation(
+        &state,
+        &state.default_animation,
+        addr,
+        headers,
+        &query,
+        options,
+    )
+    .await
+}
+
+async fn render_named_animation(
+    Extension(state): Extension<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    UrlPath((name, query)): UrlPath<(String, String)>,
+    Query(options): Query<OptionsQuery>,
+) -> Result<Response, ErrorResponse> {
+    let animation = state.animation(&name)?;
+    let options = requested_options(&state.config, options.into())?;
+    render_animation(&state, animation, addr, headers, &query, options).await
+}
+
+async fn render_animation(
+    state: &State,
+    animation: &Animation,
+    addr: SocketAddr,
+    headers: HeaderMap,
+    query: &str,
+    options: RenderOptions,
+) -> Result<Response, ErrorResponse> {
+    // NOTE: Anything that doesn't even start like a number is most likely a file some bot or
+    // browser is looking for, rather than a typo'd tempo.
+    if !query.starts_with(|c: char| c.is_ascii_digit() || matches!(c, '.' | '+' | '-')) {
+        return Err(error_response(
+            StatusCode::NOT_FOUND,
+            "NOT_FOUND",
+            "There's nothing here.",
+        ));
+    }
+    let (unquantized_bpm, format) = parse_query(query)?;
+    let tempo = quantize_bpm(state, animation, unquantized_bpm)?;
+    let bpm = tempo.bpm;
+    // NOTE: The redirect is only done if the quantized tempo quantizes to itself, as floating
+    // point error could otherwise send the client into a redirect loop.
+    if bpm != unquantized_bpm && animation.info.quantize_bpm_to_nearest_supported(bpm) == bpm {
+        // The location is relative, so that it also works for named animations and for servers
+        // behind a reverse proxy that mounts them under a subpath.
+        let extension = format.map(|format| format!(".{}", format.name()));
+        let mut location = format!("{bpm}{}", extension.unwrap_or_default());
+        let params: Vec<_> = [
+            ("w", options.width.map(|width| width.to_string())),
+            ("h", options.height.map(|height| height.to_string())),
+            (
+                "quality",
+                options.quality.map(|quality| quality.to_string()),
+            ),
+            ("dither", options.dither.map(|dither| dither.to_string())),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some(format!("{name}={}", value?)))
+        .collect();
+        if !params.is_empty() {
+            location.push('?');
+            location.push_str(&params.join("&"));
+        }
+        match state.config.canonical_redirect {
+            CanonicalRedirect::Disabled => (),
+            CanonicalRedirect::Permanent => {
+                return Ok(Redirect::permanent(&location).into_response())
+            }
+            CanonicalRedirect::Temporary => {
+                return Ok(Redirect::temporary(&location).into_response())
+            }
+        }
+    }
+    render(state, animation, addr, &headers, tempo, format, options).await
+}
+
+#[derive(Deserialize)]
+struct RenderRequest {
+    bpm: f64,
+    /// The format to render to. If not specified, it's negotiated from the Accept header like with
+    /// the GET endpoints.
+    format: Option<Format>,
+    /// The animation to render. Defaults to the default animation.
+    animation: Option<String>,
+    width: Option<u16>,
+    height: Option<u16>,
+    /// The encoder quality, from 1 to 100.
+    quality: Option<u8>,
+    dither: Option<bool>,
+}
+
+/// Renders an animation described by a JSON body, for programmatic clients that would rather not
+/// build URLs.
+async fn render_post(
+    Extension(state): Extension<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    request: Result<Json<RenderRequest>, JsonRejection>,
+) -> Result<Response, ErrorResponse> {
+    let Json(request) = request.map_err(|rejection| {
+        // NOTE: The rejection itself only says what went wrong in general, the reason why is
+        // at the bottom of its chain of sources. The first source is the specific kind of
+        // rejection, whose message is the same as the rejection's.
+        let mut reason = rejection.source().and_then(|kind| kind.source());
+        while let Some(source) = reason.and_then(|reason| reason.source()) {
+            reason = Some(source);
+        }
+        let message = match reason {
+            Some(reason) => format!("{rejection}: {reason}"),
+            None => rejection.to_string(),
+        };
+        error_response(StatusCode::BAD_REQUEST, "BAD_REQUEST", message)
+    })?;
+    let animation = match &request.animation {
+        Some(name) => state.animation(name)?,
+        None => &state.default_animation,
+    };
+    let options = RenderOptions {
+        width: request.width,
+        height: request.height,
+        quality: request.quality,
+        dither: request.dither,
+    };
+    let options = requested_options(&state.config, options)?;
+    let tempo = quantize_bpm(&state, animation, request.bpm)?;
+    render(
+        &state,
+        animation,
+        addr,
+        &headers,
+        tempo,
+        request.format,
+        options,
+    )
+    .await
+}
+
+/// Renders an animation, or fetches it from the cache. This is shared by all render endpoints.
+async fn render(
+    state: &State,
+    animation: &Animation,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+    tempo: Tempo,
+    format: Option<Format>,
+    options: RenderOptions,
+) -> Result<Response, ErrorResponse> {
+    let Tempo {
+        requested_bpm,
+        bpm,
+        output_frames,
+    } = tempo;
+    let format = format.unwrap_or_else(|| {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok());
+        Format::from_accept(accept.unwrap_or_default())
+    });
+
+    // NOTE: The same number of frames always renders to the same animation, so clients that
+    // already have it don't need to be sent it again.
+    let key = render_key(
+        animation.info.version.as_deref(),
+        output_frames,
+        format,
+        options,
+    );
+    let etag = format!("\"{key}\"");
+    // NOTE: The requested tempo is rounded, so clients syncing to the animation need to know
+    // which tempo they actually got.
+    let quantized_bpm = bpm.to_string();
+    if if_none_match(headers, &etag) {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [("ETag", etag), ("X-Quantized-BPM", quantized_bpm)],
+        )
+            .into_response());
+    }
+
+    let ip = client_ip(state.config.reverse_proxy, addr, headers);
+    let _in_flight = start_request(state, ip)?;
+    if let Some(response) = check_rate_limit(state, ip) {
+        return Ok(response);
+    }
+
+    debug!(
+        "serving {bpm} bpm (quantized from {requested_bpm} bpm) as {format:?} to {}",
+        ip
+    );
+    let gif_service = animation.gif_service.clone();
+    let request = async move { gif_service.request(output_frames, format, options).await };
+    let streaming = animation.render_service.streams_output()
+        && !animation
             .gif_service
-            .request_speed(speed)
-            .await
-            .map_err(|e| e.to_response());
-        state.waiting_clients.remove(&ip);
-        // It is safe to use the `?` operator from here onward.
-        let file = result?;
-
-        let mut response = file.into_response();
-        response
-            .headers_mut()
-            .insert("Content-Type", "image/gif".try_into().unwrap());
-        Ok(response)
+            .is_cached(output_frames, format, options);
+    let result = if streaming {
+        let stream = animation
+            .render_service
+            .subscribe(output_frames, format, options);
+        let mut request = Box::pin(request);
+        // NOTE: The response only starts once the encoder has written something, so that
+        // requests which fail early (eg. because the queue is full) still get a proper error.
+        tokio::select! {
+            result = &mut request => result,
+            _ = stream.wait_past(0) => {
+                let mut response = StreamBody::new(stream_render(stream, request)).into_response();
+                let headers = response.headers_mut();
+                headers.insert("Content-Type", format.content_type().try_into().unwrap());
+                headers.insert("ETag", etag.try_into().unwrap());
+                headers.insert("X-Quantized-BPM", quantized_bpm.try_into().unwrap());
+                return Ok(response);
+            }
+        }
     } else {
-        debug!(
-            "{} (requesting {unquantized_bpm} bpm) is being rate limited",
-            ip
-        );
-        Err(error_response(StatusCode::TOO_MANY_REQUESTS, "Hey you, behave yourself! We only have one Hat Kid, don't spam requests at her like that. Please wait until your previous GIF arrives."))
+        request.await
+    };
+    let file = result.map_err(|e| state.error_response(&e))?;
+
+    // NOTE: hyper would usually figure out the length of the body on its own, but it's set
+    // explicitly so that it survives any middleware that wraps the body.
+    let content_length = file.data.len();
+    let mut response = file.data.into_response();
+    let headers = response.headers_mut();
+    headers.insert("Content-Type", format.content_type().try_into().unwrap());
+    headers.insert("Content-Length", content_length.into());
+    headers.insert("ETag", etag.try_into().unwrap());
+    headers.insert("X-Quantized-BPM", quantized_bpm.try_into().unwrap());
+    if let Some(queue_position) = file.queue_position {
+        headers.insert("X-Queue-Position", queue_position.into());
+    }
+    Ok(response)
+}
+
+/// Sends out a render as the encoder writes it. Once the request for the render is done, whatever
+/// part of the file wasn't streamed yet is sent, which also covers the file having been cached
+/// in the meantime. Dropping the stream drops the request, which cancels the render if nobody
+/// else is waiting for it.
+fn stream_render(
+    stream: Arc<RenderStream>,
+    request: Pin<Box<impl Future<Output = Result<CachedFile, Error>> + Send + 'static>>,
+) -> impl Stream<Item = Result<Vec<u8>, Error>> {
+    futures_util::stream::unfold(Some((stream, request, 0)), |state| async move {
+        let (stream, mut request, offset) = state?;
+        tokio::select! {
+            biased;
+            _ = stream.wait_past(offset) => {
+                let chunk = stream.read_from(offset);
+                let offset = offset + chunk.len();
+                Some((Ok(chunk), Some((stream, request, offset))))
+            }
+            result = &mut request => {
+                let rest = result.map(|file| file.data.get(offset..).unwrap_or_default().to_vec());
+                Some((rest, None))
+            }
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    animation: Option<String>,
+}
+
+/// Serves a single still frame of an animation, for use in thumbnails and link previews.
+async fn preview(
+    Extension(state): Extension<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    UrlPath(query): UrlPath<String>,
+    Query(preview_query): Query<PreviewQuery>,
+) -> Result<Response, ErrorResponse> {
+    let animation = match &preview_query.animation {
+        Some(name) => state.animation(name)?,
+        None => &state.default_animation,
+    };
+    let (unquantized_bpm, extension) = split_extension(&query);
+    if let Some(extension) = extension.filter(|&extension| extension != "png") {
+        return Err(unsupported_extension(extension, &["png"]));
+    }
+    let unquantized_bpm = parse_bpm(unquantized_bpm)?;
+    let tempo = quantize_bpm(&state, animation, unquantized_bpm)?;
+
+    let ip = client_ip(state.config.reverse_proxy, addr, &headers);
+    let _in_flight = start_request(&state, ip)?;
+    if let Some(response) = check_rate_limit(&state, ip) {
+        return Ok(response);
     }
+
+    debug!("serving preview of {} bpm to {ip}", tempo.bpm);
+    let png = animation
+        .render_service
+        .render_preview(tempo.output_frames)
+        .await
+        .map_err(|e| state.error_response(&e))?;
+    Ok(([("Content-Type", "image/png")], png).into_response())
+}
+
+/// Gives each request an ID, which is attached to everything logged while handling it (including
+/// by the render and cache services) and sent back in the `X-Request-Id` header. IDs that come
+/// with the request, eg. from a reverse proxy, are kept if they look sane.
+async fn trace_request<B>(request: Request<B>, next: Next<B>) -> Response {
+    let id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            (1..=64).contains(&id.len())
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        })
+        .map(|id| id.to_owned())
+        .unwrap_or_else(|| format!("{:016x}", WyRand::new().generate::<u64>()));
+    let span = info_span!(
+        "request",
+        id,
+        method = %request.method(),
+        uri = %request.uri(),
+    );
+
+    let started_at = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    // NOTE: Streamed responses are still being sent at this point, so for them this is only the
+    // time it took to start the response.
+    let latency = started_at.elapsed();
+    let status = response.status();
+    span.in_scope(|| {
+        if status.is_server_error() {
+            warn!(status = status.as_u16(), ?latency, "request failed");
+        } else {
+            debug!(status = status.as_u16(), ?latency, "request finished");
+        }
+    });
+
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("x-request-id", id);
+    }
+    response
+}
+
+/// Returns the IP address of the client, for the purposes of rate limiting. The address forwarded
+/// by the proxy is only trusted when running behind a `reverse_proxy`.
+fn client_ip(reverse_proxy: bool, addr: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    let ip = if reverse_proxy {
+        forwarded_ip(headers).unwrap_or(addr.ip())
+    } else {
+        addr.ip()
+    };
+    // NOTE: When listening on both IPv4 and IPv6, IPv4 clients show up with IPv4-mapped IPv6
+    // addresses, which would otherwise get separate buckets from the same clients' plain IPv4
+    // addresses forwarded by a proxy.
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Counts the client's request as in flight until the returned guard is dropped, failing if the
+/// client has too many requests in flight already.
+fn start_request(state: &State, ip: IpAddr) -> Result<InFlight<'_>, ErrorResponse> {
+    state.rate_limiter.start_request(ip).ok_or_else(|| {
+        debug!("{ip} has too many requests in flight");
+        state.customize(error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            "TOO_MANY_CONCURRENT_REQUESTS",
+            "Hat Kid is still working on your other GIFs! Please wait for them before asking for \
+             more.",
+        ))
+    })
+}
+
+/// Takes a token from the client's bucket, returning the response to send instead if there are
+/// none left.
+fn check_rate_limit(state: &State, ip: IpAddr) -> Option<Response> {
+    let retry_after = state.rate_limiter.check(ip).err()?;
+    debug!(?retry_after, "{ip} is being rate limited");
+    let error = state.customize(error_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        "RATE_LIMITED",
+        "Hey you, behave yourself! We only have one Hat Kid, don't spam requests at her like that. \
+         Please wait a moment before asking for more GIFs.",
+    ));
+    // NOTE: Retry-After only supports whole seconds, so round up to not retry too early.
+    let retry_after = retry_after.as_secs_f64().ceil() as u64;
+    let headers = [("Retry-After", retry_after.to_string())];
+    Some((headers, error).into_response())
+}
+
+/// Returns the client's IP address as reported by a reverse proxy in the `X-Forwarded-For` header.
+fn forwarded_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    let header = headers.get("x-forwarded-for")?;
+    let Ok(header) = header.to_str() else {
+        debug!(?header, "X-Forwarded-For is not valid UTF-8");
+        return None;
+    };
+    // NOTE: Each proxy along the way appends the address it received the request from, so the
+    // client's address is the first one.
+    let first = header.split(',').next()?.trim();
+    match parse_forwarded_address(first) {
+        Ok(ip) => Some(ip),
+        Err(error) => {
+            debug!(header, %error, "cannot parse X-Forwarded-For IP address");
+            None
+        }
+    }
+}
+
+/// Parses an address from `X-Forwarded-For`. Besides bare IPs, some proxies include the client's
+/// port, in which case IPv6 addresses are enclosed in brackets (eg. `[2001:db8::1]:1234`.)
+fn parse_forwarded_address(address: &str) -> Result<IpAddr, AddrParseError> {
+    IpAddr::from_str(address)
+        .or_else(|_| SocketAddr::from_str(address).map(|addr| addr.ip()))
+        .or_else(|error| {
+            let bracketed = address.strip_prefix('[').and_then(|a| a.strip_suffix(']'));
+            bracketed
+                .ok_or(error)
+                .and_then(|ip| Ipv6Addr::from_str(ip).map(IpAddr::V6))
+        })
+}
+
+/// Returns whether the `If-None-Match` header matches the given ETag.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all("if-none-match")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        // NOTE: If-None-Match uses weak comparison, so the W/ prefix is ignored.
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
 }
 
 async fn pricing() -> Redirect {
@@ -219,54 +1283,384 @@ async fn pricing() -> Redirect {
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let config = std::fs::read_to_string(config::PATH).expect("failed to load config file");
-    let config: Config = toml::from_str(&config).expect("config TOML deserialization error");
+    let (raw_config, config) = config::load().unwrap_or_else(|error| panic!("{error}"));
     debug!(path = config::PATH, "loaded config file");
 
-    let animation_info = AnimationInfo::from_config(&config.animation);
-    debug!(?animation_info, "resolved animation info");
+    if std::env::args().nth(1).as_deref() == Some("purge") {
+        purge_offline(config);
+        return;
+    }
 
-    let minimum_bpm = animation_info.minimum_bpm();
-    debug!(
-        minimum_bpm,
-        "calculated minimum tempo (given {} waves at {} fps)",
-        animation_info.wave_count,
-        animation_info.fps
-    );
+    if let Err(problems) = config.validate() {
+        for problem in problems.lines() {
+            error!("{problem}");
+        }
+        error!("the config file has problems that need to be fixed before the server can start");
+        std::process::exit(1);
+    }
 
-    let render_service = RenderService::spawn(config.render_service, animation_info.clone());
-    let gif_service =
-        GifService::spawn(config.cache_service, render_service).expect("cannot spawn GIF service");
+    let spawn_animation = |name: &str, config| {
+        Animation::spawn(name, config).unwrap_or_else(|error| {
+            error!("animation `{name}`: {error}");
+            std::process::exit(1);
+        })
+    };
+    let default_animation = spawn_animation("default", config.default_animation);
+    let animations = config
+        .animations
+        .into_iter()
+        .map(|(name, config)| {
+            let animation = spawn_animation(&name, config);
+            (name, animation)
+        })
+        .collect();
 
-    let port = config.server.port;
+    let addr = SocketAddr::new(config.server.bind_address, config.server.port);
     let state = Arc::new(State {
-        animation_info,
         pages: render_index(TemplateDataConfig {
             root: config.server.root.clone(),
-            minimum_bpm,
+            minimum_bpm: default_animation.info.minimum_bpm(),
+            maximum_bpm: default_animation.info.maximum_bpm(),
         }),
+        default_animation,
+        animations,
+        rate_limiter: RateLimiter::new(
+            config.server.rate_limiting,
+            config.server.rate_limit.clone(),
+        ),
         config: config.server,
-        gif_service,
-        waiting_clients: DashSet::new(),
+        raw_config,
+    });
+    #[cfg(unix)]
+    tokio::spawn({
+        let state = Arc::clone(&state);
+        async move {
+            let mut hangups =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                    .expect("cannot listen for SIGHUP");
+            while hangups.recv().await.is_some() {
+                reload_config(&state);
+            }
+        }
+    });
+    tokio::spawn({
+        let state = Arc::clone(&state);
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                state.rate_limiter.prune();
+            }
+        }
     });
 
     let app = Router::new()
         .route("/", get(index))
         .route("/index.html", get(index))
         .route("/man", get(man))
+        .route("/healthz", get(healthz))
+        .route("/metrics", get(metrics))
+        .route("/api/animation", get(animation_metadata))
+        .route("/api/bpms", get(bpms))
+        .route("/api/queue", get(queue_status))
+        .route("/api/cache", get(cache_status))
+        .route("/admin/purge", post(purge))
+        .route("/preview/:query", get(preview))
         .route("/pricing", get(pricing))
         .route("/font.ttf", get(font))
-        .route("/:query", get(render_animation));
+        .route("/favicon.ico", get(favicon))
+        .route("/og.png", get(og_image));
+    let render_routes = Router::new()
+        // NOTE: The router requires parameters in the same position to have the same name, so the
+        // default animation's query is also named `animation` here.
+        .route("/:animation", get(render_default_animation))
+        .route("/:animation/:query", get(render_named_animation))
+        .route("/render", post(render_post))
+        .layer(cors_layer(&state.config.cors_allowed_origins));
+    let app = app.merge(render_routes);
     #[cfg(debug_assertions)]
     let app = app //
         .route("/index.js", get(js))
         .route("/style.css", get(css));
-    let app = app.layer(Extension(state));
+    let app = app
+        .layer(Extension(Arc::clone(&state)))
+        .layer(middleware::from_fn(trace_request));
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = listen(addr).expect("failed to bind to address");
     info!("listening on {addr}");
-    axum::Server::bind(&addr)
+    axum::Server::from_tcp(listener)
+        .expect("failed to start server")
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .expect("failed to start server");
+
+    shut_down(&state).await;
+}
+
+/// Purges the caches of all animations without starting the server, for use with
+/// `smugdancer purge`.
+fn purge_offline(config: Config) {
+    let animations =
+        std::iter::once(("default".to_owned(), config.default_animation)).chain(config.animations);
+    for (name, config) in animations {
+        let database = config
+            .cache_service
+            .setup()
+            .expect("cannot open cache database");
+        let purged = cache_service::purge(&config.cache_service.cache_dir, &database)
+            .expect("cannot purge cache");
+        info!(
+            animation = name,
+            files = purged.files,
+            bytes = purged.bytes,
+            "purged cache"
+        );
+    }
+}
+
+/// Opens the socket the server listens on. IPv6 sockets also accept IPv4 connections, so that
+/// binding to `::` listens on all interfaces regardless of the system's default.
+fn listen(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Creates the layer that allows other origins to fetch rendered animations.
+fn cors_layer(allowed_origins: &[String]) -> CorsLayer {
+    let allow_origin = if allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(allowed_origins.iter().map(|origin| {
+            HeaderValue::from_str(origin).expect("invalid origin in cors_allowed_origins")
+        }))
+    };
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::CONTENT_TYPE])
+        .expose_headers([
+            header::ETAG,
+            header::RETRY_AFTER,
+            header::HeaderName::from_static("x-queue-position"),
+            header::HeaderName::from_static("x-quantized-bpm"),
+            header::HeaderName::from_static("x-request-id"),
+        ])
+        // NOTE: The layer replaces any Vary header set by the handler, so Accept is listed here.
+        // Render responses without an extension in the path depend on it, as the format is
+        // negotiated from it.
+        .vary([
+            header::ORIGIN,
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            header::ACCEPT,
+        ])
+}
+
+/// Re-reads the config file upon SIGHUP. Only the rate limits and the cache limits are applied;
+/// changes to anything else are logged as needing a restart.
+fn reload_config(state: &State) {
+    info!("received SIGHUP, reloading the config file");
+    let (raw_config, config) = match config::load() {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            error!("cannot reload the config file, keeping the current config: {error}");
+            return;
+        }
+    };
+    if let Err(problems) = config.validate() {
+        for problem in problems.lines() {
+            error!("{problem}");
+        }
+        error!("the config file has problems, keeping the current config");
+        return;
+    }
+
+    for key in config::changes_requiring_restart(&state.raw_config, &raw_config) {
+        warn!("`{key}` was changed, which only takes effect after a restart");
+    }
+    state
+        .rate_limiter
+        .reconfigure(config.server.rate_limiting, config.server.rate_limit);
+    for (name, animation) in state.all_animations() {
+        let config = match name {
+            "default" => Some(&config.default_animation),
+            name => config.animations.get(name),
+        };
+        if let Some(config) = config {
+            animation.gif_service.set_limits(&config.cache_service);
+        }
+    }
+    info!("config reloaded");
+}
+
+/// Resolves once the server is asked to stop, either with Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("cannot listen for Ctrl-C")
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("cannot listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("received Ctrl-C"),
+        _ = terminate => info!("received SIGTERM"),
+    }
+    info!("shutting down, no longer accepting new requests");
+}
+
+/// Lets the render queue drain and flushes the cache once the server has stopped accepting
+/// requests.
+async fn shut_down(state: &State) {
+    let timeout = Duration::from_secs(state.config.shutdown_timeout);
+    let queue_depth = || {
+        state
+            .all_animations()
+            .map(|(_, animation)| animation.render_service.queue_depth())
+            .sum::<usize>()
+    };
+    info!(?timeout, "waiting for the render queues to drain");
+    let drained = tokio::time::timeout(timeout, async {
+        while queue_depth() > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            abandoned = queue_depth(),
+            "render queues did not drain in time"
+        );
+    }
+
+    for (name, animation) in state.all_animations() {
+        info!(name, "flushing the cache database");
+        if let Err(error) = animation.gif_service.flush().await {
+            warn!(name, "cannot flush the cache database: {error}");
+        }
+    }
+    info!("shutdown complete");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{client_ip, parse_bpm, parse_forwarded_address, split_extension};
+
+    fn forwarded_for(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn split_extension_keeps_fractional_tempos() {
+        assert_eq!(split_extension("128"), ("128", None));
+        assert_eq!(split_extension("128.gif"), ("128", Some("gif")));
+        assert_eq!(split_extension("127.5"), ("127.5", None));
+        assert_eq!(split_extension("127.5.webp"), ("127.5", Some("webp")));
+        assert_eq!(split_extension("128.gif.gif"), ("128.gif", Some("gif")));
+        assert_eq!(split_extension("1e3"), ("1e3", None));
+        assert_eq!(split_extension("0x10"), ("0x10", None));
+    }
+
+    #[test]
+    fn parse_bpm_accepts_decimals() {
+        assert_eq!(parse_bpm("128").ok(), Some(128.0));
+        assert_eq!(parse_bpm("127.5").ok(), Some(127.5));
+        assert_eq!(parse_bpm("+128").ok(), Some(128.0));
+        assert_eq!(parse_bpm(".5").ok(), Some(0.5));
+        assert_eq!(parse_bpm("128.").ok(), Some(128.0));
+    }
+
+    #[test]
+    fn parse_bpm_rejects_everything_else() {
+        for bpm in [
+            "", ".", "0x10", "inf", "-inf", "nan", "NaN", "1e3", "128.gif", " 128",
+        ] {
+            assert!(parse_bpm(bpm).is_err(), "{bpm:?} should be rejected");
+        }
+        // Extensions are only split off once, so a doubled one is left in the tempo.
+        let (bpm, _) = split_extension("128.gif.gif");
+        assert!(parse_bpm(bpm).is_err());
+    }
+
+    #[test]
+    fn parse_forwarded_addresses() {
+        let parse = |address| parse_forwarded_address(address).ok();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            parse("203.0.113.7"),
+            Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)))
+        );
+        assert_eq!(
+            parse("203.0.113.7:1234"),
+            Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)))
+        );
+        assert_eq!(parse("2001:db8::1"), Some(v6));
+        assert_eq!(parse("[2001:db8::1]"), Some(v6));
+        assert_eq!(parse("[2001:db8::1]:1234"), Some(v6));
+        assert_eq!(parse("[::1]:8080"), Some(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        for address in [
+            "",
+            "unknown",
+            "[203.0.113.7]",
+            "[2001:db8::1",
+            "2001:db8::1]:1234",
+        ] {
+            assert_eq!(parse(address), None, "{address:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn client_ip_from_proxy() {
+        let addr: SocketAddr = "[::1]:8080".parse().unwrap();
+        let headers = forwarded_for("[2001:db8::1]:1234, 10.0.0.1");
+        assert_eq!(
+            client_ip(true, addr, &headers),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        // The header can be set by anyone, so it's ignored unless there is a proxy setting it.
+        assert_eq!(
+            client_ip(false, addr, &headers),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+        // Unparseable headers fall back to the proxy's address.
+        let headers = forwarded_for("unknown");
+        assert_eq!(
+            client_ip(true, addr, &headers),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+    }
+
+    #[test]
+    fn client_ip_unmaps_ipv4() {
+        let ipv4 = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        let mapped: SocketAddr = "[::ffff:203.0.113.7]:1234".parse().unwrap();
+        assert_eq!(client_ip(false, mapped, &HeaderMap::new()), ipv4);
+        let addr: SocketAddr = "[::1]:8080".parse().unwrap();
+        let headers = forwarded_for("::ffff:203.0.113.7");
+        assert_eq!(client_ip(true, addr, &headers), ipv4);
+        let headers = forwarded_for("203.0.113.7");
+        assert_eq!(client_ip(true, addr, &headers), ipv4);
+    }
 }
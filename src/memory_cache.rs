@@ -0,0 +1,82 @@
+//! In-memory cache of recently served files, in front of the cache directory.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+struct Entry {
+    data: Vec<u8>,
+    /// When the file was last used, in ticks of `MemoryCache::clock`.
+    last_used: u64,
+}
+
+/// Keeps the most recently used files in memory, up to a total size. Once it's full, the least
+/// recently used files make room for new ones.
+pub struct MemoryCache {
+    capacity: u64,
+    size: u64,
+    /// Advances with every use of a file.
+    clock: u64,
+    files: HashMap<PathBuf, Entry>,
+}
+
+impl MemoryCache {
+    /// Creates a cache holding up to `capacity` bytes. A capacity of 0 disables it.
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            clock: 0,
+            files: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, path: &Path) -> Option<Vec<u8>> {
+        self.clock += 1;
+        let entry = self.files.get_mut(path)?;
+        entry.last_used = self.clock;
+        Some(entry.data.clone())
+    }
+
+    /// Adds a file to the cache, removing the least recently used ones if there isn't enough room.
+    /// Files larger than the whole cache are not kept.
+    pub fn insert(&mut self, path: PathBuf, data: &[u8]) {
+        let len = data.len() as u64;
+        if len > self.capacity {
+            return;
+        }
+        self.remove(&path);
+        while self.size + len > self.capacity {
+            let least_recently_used = self
+                .files
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            match least_recently_used {
+                Some(path) => self.remove(&path),
+                None => break,
+            }
+        }
+        self.clock += 1;
+        self.size += len;
+        self.files.insert(
+            path,
+            Entry {
+                data: data.to_owned(),
+                last_used: self.clock,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.files.remove(path) {
+            self.size -= entry.data.len() as u64;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.size = 0;
+    }
+}
@@ -0,0 +1,162 @@
+//! Render metrics, exported in the Prometheus text format.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+
+/// Bucket upper bounds (in seconds) used for all durations.
+const DURATION_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Counts observations of a duration in buckets.
+#[derive(Default)]
+pub struct Histogram {
+    inner: Mutex<HistogramInner>,
+}
+
+#[derive(Default)]
+struct HistogramInner {
+    /// The number of observations falling into each bucket, not including the ones before it.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut inner = self.inner.lock();
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|&le| seconds <= le) {
+            inner.buckets[bucket] += 1;
+        }
+        inner.sum += seconds;
+        inner.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, animation: &str) {
+        let inner = self.inner.lock();
+        let mut cumulative = 0;
+        for (le, count) in DURATION_BUCKETS.iter().zip(inner.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{animation=\"{animation}\",le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{animation=\"{animation}\",le=\"+Inf\"}} {}",
+            inner.count
+        );
+        let _ = writeln!(out, "{name}_sum{{animation=\"{animation}\"}} {}", inner.sum);
+        let _ = writeln!(
+            out,
+            "{name}_count{{animation=\"{animation}\"}} {}",
+            inner.count
+        );
+    }
+}
+
+/// Metrics collected by the services of a single animation.
+#[derive(Default)]
+pub struct Metrics {
+    /// How long renders wait in the queue for a free job slot.
+    pub queue_wait: Histogram,
+    /// How long the encoder takes to render an animation, including retries.
+    pub encode: Histogram,
+    /// Requests served from the cache.
+    pub cache_hits: AtomicU64,
+    /// Requests that needed a render.
+    pub cache_misses: AtomicU64,
+    /// Rendered animations written to the cache.
+    pub cache_writes: AtomicU64,
+    /// The total size of the animations returned by the cache, whether they were cached or not.
+    pub cache_bytes_served: AtomicU64,
+}
+
+/// Renders the metrics of the given animations.
+pub fn export(animations: &[(&str, &Metrics)]) -> String {
+    let mut out = String::new();
+
+    write_histograms(
+        &mut out,
+        "smugdancer_render_queue_wait_seconds",
+        "Time renders spent waiting for a free job slot.",
+        animations,
+        |metrics| &metrics.queue_wait,
+    );
+    write_histograms(
+        &mut out,
+        "smugdancer_render_encode_seconds",
+        "Time the encoder took to render an animation.",
+        animations,
+        |metrics| &metrics.encode,
+    );
+
+    let name = "smugdancer_cache_requests_total";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Requests for animations, by whether they were cached."
+    );
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for &(animation, metrics) in animations {
+        for (result, counter) in [
+            ("hit", &metrics.cache_hits),
+            ("miss", &metrics.cache_misses),
+        ] {
+            let _ = writeln!(
+                out,
+                "{name}{{animation=\"{animation}\",result=\"{result}\"}} {}",
+                counter.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    let name = "smugdancer_cache_writes_total";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Rendered animations written to the cache."
+    );
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for &(animation, metrics) in animations {
+        let _ = writeln!(
+            out,
+            "{name}{{animation=\"{animation}\"}} {}",
+            metrics.cache_writes.load(Ordering::Relaxed)
+        );
+    }
+
+    let name = "smugdancer_cache_served_bytes_total";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Bytes of animations returned by the cache."
+    );
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for &(animation, metrics) in animations {
+        let _ = writeln!(
+            out,
+            "{name}{{animation=\"{animation}\"}} {}",
+            metrics.cache_bytes_served.load(Ordering::Relaxed)
+        );
+    }
+
+    out
+}
+
+/// Writes one of the histograms of every animation.
+fn write_histograms(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    animations: &[(&str, &Metrics)],
+    histogram: fn(&Metrics) -> &Histogram,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} histogram");
+    for &(animation, metrics) in animations {
+        histogram(metrics).write(out, name, animation);
+    }
+}
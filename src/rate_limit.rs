@@ -0,0 +1,298 @@
+//! Per-IP rate limiting using token buckets.
+
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use parking_lot::RwLock;
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// How many requests per second each IP is allowed to make in the long run. Must be greater
+    /// than 0.
+    pub rate: f64,
+    /// How many requests an IP can make in a quick burst before being limited to `rate`. Must be
+    /// at least 1.
+    pub burst: f64,
+    /// How many requests from one IP may be waiting for an animation at the same time. Unlimited
+    /// if not set. Clients behind a reverse proxy are only told apart if `reverse_proxy` is
+    /// enabled, otherwise they all share the proxy's IP and therefore this limit.
+    pub max_concurrent: Option<usize>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            rate: 0.5,
+            burst: 4.0,
+            max_concurrent: None,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Checks the config for limits that can't work, returning a description of each one.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !(self.rate.is_finite() && self.rate > 0.0) {
+            problems.push(format!(
+                "`rate` must be greater than 0, or clients could never make another request after \
+                 using up their burst, but it is {}",
+                self.rate
+            ));
+        }
+        // NOTE: Each request takes a whole token, and buckets never hold more than `burst`.
+        if !(self.burst.is_finite() && self.burst >= 1.0) {
+            problems.push(format!(
+                "`burst` must be at least 1, or no requests can be made, but it is {}",
+                self.burst
+            ));
+        }
+        if self.max_concurrent == Some(0) {
+            problems.push("`max_concurrent` must be at least 1, or no requests can be made".into());
+        }
+        problems
+    }
+}
+
+/// The longest a client is ever told to wait before trying again.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, config: &RateLimitConfig, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.rate).min(config.burst);
+        self.last_refill = now;
+    }
+}
+
+pub struct RateLimiter {
+    enabled: AtomicBool,
+    config: RwLock<RateLimitConfig>,
+    buckets: DashMap<IpAddr, Bucket>,
+    /// The number of requests each IP has in flight. IPs without any are removed.
+    in_flight: DashMap<IpAddr, usize>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter. If it's not `enabled`, all requests are let through.
+    pub fn new(enabled: bool, config: RateLimitConfig) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+            config: RwLock::new(config),
+            buckets: DashMap::new(),
+            in_flight: DashMap::new(),
+        }
+    }
+
+    /// Changes the limits while the server is running. IPs keep the tokens they have left, up to
+    /// the new burst size.
+    pub fn reconfigure(&self, enabled: bool, config: RateLimitConfig) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        *self.config.write() = config;
+    }
+
+    /// Takes a token from the IP's bucket. If the bucket is empty, returns how long the client
+    /// should wait before trying again.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let config = self.config.read();
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(bucket_key(ip)).or_insert(Bucket {
+            tokens: config.burst,
+            last_refill: now,
+        });
+        bucket.refill(&config, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            // NOTE: Limits that slipped past validation shouldn't take the request down with a
+            // panic, so waits that can't be represented are capped like any other long wait.
+            let retry_after = Duration::try_from_secs_f64(missing / config.rate)
+                .unwrap_or(MAX_RETRY_AFTER)
+                .min(MAX_RETRY_AFTER);
+            Err(retry_after)
+        }
+    }
+
+    /// Counts a request from the IP as in flight until the returned guard is dropped. Returns
+    /// `None` if the IP already has as many requests in flight as it's allowed to.
+    pub fn start_request(&self, ip: IpAddr) -> Option<InFlight<'_>> {
+        let max_concurrent = if self.enabled.load(Ordering::Relaxed) {
+            self.config.read().max_concurrent
+        } else {
+            None
+        };
+        let key = bucket_key(ip);
+        // NOTE: Requests are counted even while limiting is disabled, so that the counts are
+        // right if it's enabled by reloading the config.
+        let mut count = self.in_flight.entry(key).or_insert(0);
+        if max_concurrent.is_some_and(|max_concurrent| *count >= max_concurrent) {
+            return None;
+        }
+        *count += 1;
+        Some(InFlight { limiter: self, key })
+    }
+
+    /// Forgets about IPs whose buckets have refilled completely, as they're indistinguishable
+    /// from IPs that haven't made any requests.
+    pub fn prune(&self) {
+        let config = self.config.read();
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(&config, now);
+            bucket.tokens < config.burst
+        });
+    }
+}
+
+/// A request counted towards its IP's limit of concurrent requests, until this is dropped.
+pub struct InFlight<'a> {
+    limiter: &'a RateLimiter,
+    key: IpAddr,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.limiter.in_flight.remove_if_mut(&self.key, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+/// Returns the key of the bucket an IP belongs to. IPv6 clients are usually given a whole /64
+/// subnet to pick addresses from, so they share one bucket per subnet; otherwise they could get a
+/// fresh bucket for every request.
+fn bucket_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => {
+            let prefix = u128::from(v6) & !(u128::MAX >> 64);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use super::{RateLimitConfig, RateLimiter, MAX_RETRY_AFTER};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(
+            true,
+            RateLimitConfig {
+                rate: 0.001,
+                burst: 2.0,
+                max_concurrent: Some(1),
+            },
+        )
+    }
+
+    #[test]
+    fn validate_rejects_unusable_limits() {
+        let valid = RateLimitConfig::default();
+        assert!(valid.validate().is_empty());
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let config = RateLimitConfig {
+                rate,
+                ..valid.clone()
+            };
+            assert_eq!(config.validate().len(), 1, "rate {rate} should be rejected");
+        }
+        for burst in [0.0, 0.5, -1.0, f64::NAN, f64::INFINITY] {
+            let config = RateLimitConfig {
+                burst,
+                ..valid.clone()
+            };
+            assert_eq!(
+                config.validate().len(),
+                1,
+                "burst {burst} should be rejected"
+            );
+        }
+        let config = RateLimitConfig {
+            max_concurrent: Some(0),
+            ..valid
+        };
+        assert_eq!(config.validate().len(), 1);
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        for rate in [0.0, -1.0, 1e-12] {
+            let limiter = RateLimiter::new(
+                true,
+                RateLimitConfig {
+                    rate,
+                    burst: 1.0,
+                    max_concurrent: None,
+                },
+            );
+            assert!(limiter.check(ip("203.0.113.7")).is_ok());
+            let retry_after = limiter.check(ip("203.0.113.7")).unwrap_err();
+            assert!(
+                retry_after <= MAX_RETRY_AFTER,
+                "rate {rate}: {retry_after:?}"
+            );
+        }
+        let limiter = limiter();
+        limiter.check(ip("203.0.113.7")).unwrap();
+        limiter.check(ip("203.0.113.7")).unwrap();
+        let retry_after = limiter.check(ip("203.0.113.7")).unwrap_err();
+        assert!(retry_after > Duration::from_secs(1));
+    }
+
+    #[test]
+    fn ipv6_subnets_share_a_bucket() {
+        let limiter = limiter();
+        assert!(limiter.check(ip("2001:db8:0:1::1")).is_ok());
+        assert!(limiter
+            .check(ip("2001:db8:0:1:ffff:ffff:ffff:ffff"))
+            .is_ok());
+        assert!(limiter.check(ip("2001:db8:0:1::2")).is_err());
+        // Other /64 subnets, even neighbouring ones, have buckets of their own.
+        assert!(limiter.check(ip("2001:db8:0:2::1")).is_ok());
+        assert!(limiter.check(ip("2001:db8::1")).is_ok());
+    }
+
+    #[test]
+    fn ipv4_addresses_have_their_own_buckets() {
+        let limiter = limiter();
+        assert!(limiter.check(ip("203.0.113.7")).is_ok());
+        assert!(limiter.check(ip("203.0.113.7")).is_ok());
+        assert!(limiter.check(ip("203.0.113.7")).is_err());
+        assert!(limiter.check(ip("203.0.113.8")).is_ok());
+    }
+
+    #[test]
+    fn ipv6_subnets_share_concurrent_requests() {
+        let limiter = limiter();
+        let in_flight = limiter.start_request(ip("2001:db8:0:1::1"));
+        assert!(in_flight.is_some());
+        assert!(limiter.start_request(ip("2001:db8:0:1::2")).is_none());
+        assert!(limiter.start_request(ip("2001:db8:0:2::1")).is_some());
+        drop(in_flight);
+        assert!(limiter.start_request(ip("2001:db8:0:1::2")).is_some());
+    }
+}
@@ -1,48 +1,391 @@
-use std::{ffi::OsString, path::PathBuf, process::Stdio, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    ffi::OsString,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
 
 use dashmap::DashMap;
+use parking_lot::Mutex;
 use serde::Deserialize;
 use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
     process::Command,
-    sync::{mpsc, oneshot, Semaphore},
+    sync::{mpsc, oneshot, Notify, Semaphore},
+    task::JoinHandle,
 };
-use tracing::{debug, error, info, instrument, trace};
+use tracing::{debug, error, info, instrument, trace, warn, Instrument, Span};
 
-use crate::{animation_info::AnimationInfo, common::Error};
+use crate::{
+    animation_info::AnimationInfo,
+    common::{Error, Format, RenderOptions},
+    metrics::Metrics,
+};
 
 #[derive(Deserialize, Clone)]
 pub struct RenderServiceConfig {
+    /// The encoder used for formats that don't have one of their own in `encoders`.
+    #[serde(flatten)]
+    pub default_encoder: EncoderConfig,
+    /// Encoders to use for specific output formats instead of the default one, eg.
+    /// `[render_service.encoders.webp]`. Their flags are expanded the same way.
+    #[serde(default)]
+    pub encoders: HashMap<Format, EncoderConfig>,
+    /// The directory containing the animation's frames as individual image files. Only needed
+    /// if the encoder is passed `{input_filenames}`.
+    #[serde(default)]
+    pub frames_dir: Option<PathBuf>,
+    /// The filename of each frame inside `frames_dir`. `{n}` is replaced with the 1-based index of
+    /// the frame; `{n:4}` pads it with zeros to 4 digits.
+    #[serde(default = "default_frame_name_template")]
+    pub frame_name_template: String,
+    /// Flags to pass onto the encoder when extracting a single frame as a PNG preview. The flag
+    /// `{frame_index}` is replaced with the index of the frame. Previews are disabled if this is
+    /// not set.
+    pub preview_flags: Option<Vec<String>>,
+    /// The maximum number of encoding jobs that are allowed to run at a time.
+    pub max_jobs: usize,
+    /// The most frames a rendered animation may have. Requests for speeds that would need more
+    /// are rejected as too slow, which bounds how much memory a single render can take.
+    ///
+    /// The animation played at its own tempo (`natural_bpm`) has as many frames as the animation
+    /// itself, and slower speeds are never rendered, so this defaults to and cannot go above the
+    /// frame count. Setting it lower raises the slowest tempo that can be rendered (`minimum_bpm`)
+    /// to `natural_bpm * frame_count / max_output_frames`.
+    #[serde(default)]
+    pub max_output_frames: Option<usize>,
+    /// The maximum number of distinct renders that may be queued or in progress at a time.
+    /// Requests that would start another render are turned away once this is reached.
+    #[serde(default = "default_max_pending")]
+    pub max_pending: usize,
+    /// How many times to retry a render if the encoder fails. The delay between attempts starts
+    /// at half a second and doubles with each retry.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// How long an encoding job may run before it's killed, in seconds.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Whether to send animations to clients while they're still being encoded, instead of
+    /// waiting for the encoder to finish. Since the response has already started by the time the
+    /// encoder could fail, failed renders are sent as truncated files.
+    #[serde(default)]
+    pub stream: bool,
+    /// The order in which queued renders are started.
+    #[serde(default)]
+    pub scheduling: Scheduling,
+    /// The directory encoders that are passed `{output}` write their output to. Defaults to the
+    /// system's temporary directory, which on some systems is kept in memory.
+    #[serde(default)]
+    pub output_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scheduling {
+    /// Start renders in the order they were requested.
+    #[default]
+    Fifo,
+    /// Start renders that were requested most often recently first.
+    Popularity,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EncoderConfig {
     /// The path to the encoder executable.
     pub encoder: PathBuf,
-    /// Flags to pass onto the encoder. Among these flags must be one whose contents are
-    /// `{input_filenames}`, which is expanded to a list of filenames for the encoder.
+    /// Flags to pass onto the encoder. Among these flags must be one containing `{frame_indices}`,
+    /// which is expanded to the 1-based indices of the frames to encode, or `{input_filenames}`,
+    /// which is expanded to the paths of those frames (see `frames_dir`.)
+    /// `{format}` is replaced with the requested output format (`gif` or `webp`.)
+    /// `{output}` is replaced with the path of a temporary file, which the encoder should write
+    /// the animation to instead of its standard output. Encoders that buffer everything they
+    /// write to a pipe can then write it out as they go; the file is read back once the encoder
+    /// finishes and is removed afterwards. This doesn't work with `stream`.
+    /// `{width}`, `{height}`, `{quality}` (1 to 100), and `{dither}` (`true` or `false`) are
+    /// replaced with the options requested by the client; flags containing them are left out if
+    /// the client didn't request that option, so they should be written in the form
+    /// `--height={height}`.
     pub encoder_flags: Vec<String>,
-    /// The maximum number of encoding jobs that are allowed to run at a time.
-    pub max_jobs: usize,
+    /// Whether to write the frames expanded from `{frame_indices}` or `{input_filenames}` to the
+    /// encoder's standard input instead of passing them as arguments. Slow speeds select a lot of
+    /// frames, which can otherwise run into the operating system's limit on argument length.
+    #[serde(default)]
+    pub frames_on_stdin: bool,
+    /// What to write after each frame when `frames_on_stdin` is enabled.
+    #[serde(default = "default_frame_separator")]
+    pub frame_separator: String,
+}
+
+impl RenderServiceConfig {
+    /// Checks the config for mistakes that would otherwise only surface once something is
+    /// rendered, returning a description of each one.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.max_jobs == 0 {
+            problems.push("`max_jobs` must be at least 1, or nothing can ever be rendered".into());
+        }
+        let encoders =
+            [("the encoder".to_owned(), &self.default_encoder)]
+                .into_iter()
+                .chain(self.encoders.iter().map(|(format, encoder)| {
+                    (format!("the encoder for {}", format.name()), encoder)
+                }));
+        for (name, encoder) in encoders {
+            if !is_executable(&encoder.encoder) {
+                problems.push(format!(
+                    "{name} ({}) does not exist or is not executable",
+                    encoder.encoder.display()
+                ));
+            }
+            let selects_frames = encoder
+                .encoder_flags
+                .iter()
+                .any(|flag| flag.contains("{frame_indices}") || flag.contains("{input_filenames}"));
+            if !selects_frames {
+                problems.push(format!(
+                    "the flags of {name} contain neither `{{frame_indices}}` nor \
+                     `{{input_filenames}}`, so it cannot be told which frames to encode"
+                ));
+            }
+            if self.stream && encoder.writes_to_file() {
+                problems.push(format!(
+                    "the flags of {name} contain `{{output}}`, but `stream` is enabled, which \
+                     needs the encoder to write to its standard output"
+                ));
+            }
+        }
+        if let Some(output_dir) = &self.output_dir {
+            if !output_dir.is_dir() {
+                problems.push(format!(
+                    "`output_dir` ({}) does not exist or is not a directory",
+                    output_dir.display()
+                ));
+            }
+        }
+
+        if self.uses_filenames() {
+            match self.frame_path(1) {
+                None => problems.push(
+                    "`{input_filenames}` is used in the encoder flags, but `frames_dir` is not set"
+                        .into(),
+                ),
+                Some(first_frame) if !first_frame.is_file() => problems.push(format!(
+                    "the first frame of the animation ({}) does not exist",
+                    first_frame.display()
+                )),
+                Some(_) => (),
+            }
+        }
+        problems
+    }
+
+    /// Checks that the config is usable, failing with the first problem found by `validate`.
+    pub fn setup(&self) -> Result<(), Error> {
+        match self.validate().into_iter().next() {
+            Some(problem) => Err(Error::Misconfigured(problem)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that every frame of the animation exists, if the encoder is passed their paths.
+    /// Otherwise a missing frame would only be noticed once a render that needs it fails.
+    pub fn check_frames(&self, frame_count: usize) -> Result<(), Error> {
+        if !self.uses_filenames() {
+            return Ok(());
+        }
+        let missing: Vec<_> = (1..=frame_count)
+            .filter_map(|index| self.frame_path(index))
+            .filter(|path| !path.is_file())
+            .collect();
+        match missing.first() {
+            Some(first) => Err(Error::Misconfigured(format!(
+                "{} of the animation's {frame_count} frames are missing, starting with {}",
+                missing.len(),
+                first.display()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns whether any of the encoders is passed the paths of frames.
+    fn uses_filenames(&self) -> bool {
+        self.encoders
+            .values()
+            .chain([&self.default_encoder])
+            .flat_map(|config| &config.encoder_flags)
+            .any(|flag| flag.contains("{input_filenames}"))
+    }
+
+    /// Returns the encoder used to render the given format.
+    fn encoder_for(&self, format: Format) -> &EncoderConfig {
+        self.encoders.get(&format).unwrap_or(&self.default_encoder)
+    }
+
+    /// Returns the path to the frame with the given 1-based index, or `None` if `frames_dir` is
+    /// not set.
+    fn frame_path(&self, index: usize) -> Option<PathBuf> {
+        let dir = self.frames_dir.as_ref()?;
+        Some(dir.join(frame_name(&self.frame_name_template, index)))
+    }
+}
+
+impl EncoderConfig {
+    /// Returns whether the encoder writes its output to a file passed as `{output}`.
+    fn writes_to_file(&self) -> bool {
+        self.encoder_flags
+            .iter()
+            .any(|flag| flag.contains("{output}"))
+    }
+}
+
+/// A temporary file an encoder writes its output to. The file is removed once this is dropped,
+/// including when the encoder fails or times out.
+struct OutputFile {
+    path: PathBuf,
+}
+
+impl OutputFile {
+    fn new(dir: Option<&Path>, format: Format) -> Self {
+        // NOTE: The process ID keeps files of several servers sharing a directory apart.
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let name = format!("smugdancer-{}-{id}.{}", std::process::id(), format.name());
+        let dir = dir.map_or_else(std::env::temp_dir, Path::to_owned);
+        Self {
+            path: dir.join(name),
+        }
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        // The encoder may have failed before creating the file, so errors are ignored.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Expands `{n}` or `{n:width}` in a frame filename template.
+fn frame_name(template: &str, index: usize) -> String {
+    if let Some(start) = template.find("{n:") {
+        let rest = &template[start + 3..];
+        if let Some(end) = rest.find('}') {
+            if let Ok(width) = rest[..end].parse::<usize>() {
+                return format!("{}{index:0width$}{}", &template[..start], &rest[end + 1..]);
+            }
+        }
+    }
+    template.replace("{n}", &index.to_string())
+}
+
+fn default_frame_name_template() -> String {
+    "{n}.png".into()
+}
+
+fn default_frame_separator() -> String {
+    "\n".into()
+}
+
+fn default_max_pending() -> usize {
+    64
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_timeout() -> u64 {
+    120
 }
 
+/// Identifies a render: the number of output frames, the format, and the options. The number of
+/// output frames alone determines which input frames are sampled, so speeds that end up with the
+/// same number of frames share a single render.
+type RenderKey = (usize, Format, RenderOptions);
+
+/// A render waiting for a free job slot, along with when it was requested and the span of the
+/// request that started it.
+type PendingRender = (RenderKey, Instant, Span);
+
 pub struct RenderService {
     config: RenderServiceConfig,
     animation_info: AnimationInfo,
-    queues: DashMap<u64, Vec<oneshot::Sender<RenderResult>>>,
-    render_requests: mpsc::Sender<f64>,
-    render_jobs: Semaphore,
+    queues: DashMap<RenderKey, Vec<oneshot::Sender<RenderResult>>>,
+    /// Running render jobs, so that they can be stopped once nobody is waiting for them.
+    jobs: DashMap<RenderKey, JoinHandle<()>>,
+    /// The output of renders in progress, if streaming is enabled. Streams are dropped once the
+    /// render and everyone reading it are done with them.
+    streams: DashMap<RenderKey, Weak<RenderStream>>,
+    /// Renders to start, along with the span of the request that started them.
+    render_requests: mpsc::Sender<(RenderKey, Span)>,
+    render_jobs: Arc<Semaphore>,
+    popularity: Mutex<Popularity>,
+    metrics: Arc<Metrics>,
+}
+
+/// Counts how often each render is requested, for `Scheduling::Popularity`.
+#[derive(Default)]
+struct Popularity {
+    counts: HashMap<RenderKey, u32>,
+    recorded: u32,
+}
+
+impl Popularity {
+    /// How many requests are recorded before all counts are halved.
+    const DECAY_INTERVAL: u32 = 256;
+
+    fn record(&mut self, key: RenderKey) {
+        *self.counts.entry(key).or_default() += 1;
+        self.recorded += 1;
+        // NOTE: Counts decay over time, so that renders which used to be popular a while ago
+        // don't keep getting ahead of the rest. This also keeps the map from growing forever.
+        if self.recorded >= Self::DECAY_INTERVAL {
+            self.recorded = 0;
+            self.counts.retain(|_, count| {
+                *count /= 2;
+                *count > 0
+            });
+        }
+    }
+
+    fn get(&self, key: &RenderKey) -> u32 {
+        self.counts.get(key).copied().unwrap_or(0)
+    }
 }
 
 impl RenderService {
     pub fn spawn(
         config: RenderServiceConfig,
         animation_info: AnimationInfo,
-    ) -> RenderServiceHandle {
+        metrics: Arc<Metrics>,
+    ) -> Result<RenderServiceHandle, Error> {
+        config.setup()?;
+        config.check_frames(animation_info.frame_count)?;
+
         let (requests_tx, mut requests_rx) = mpsc::channel(32);
-        let (renders_tx, mut renders_rx) = mpsc::channel(32);
+        // NOTE: The render channel never holds more than the maximum number of pending renders,
+        // since each one starts a new queue.
+        let (renders_tx, mut renders_rx) = mpsc::channel(config.max_pending.max(1));
         let (completed_renders_tx, mut completed_renders_rx) = mpsc::channel(8);
+        // NOTE: Cancellations are sent from destructors, which cannot wait for the channel to
+        // have space, hence the channel is unbounded.
+        let (cancellations_tx, mut cancellations_rx) = mpsc::unbounded_channel();
 
         let service = Arc::new(RenderService {
             animation_info,
             queues: DashMap::new(),
+            jobs: DashMap::new(),
+            streams: DashMap::new(),
             render_requests: renders_tx,
-            render_jobs: Semaphore::new(config.max_jobs),
+            render_jobs: Arc::new(Semaphore::new(config.max_jobs)),
+            popularity: Mutex::new(Popularity::default()),
+            metrics,
             config,
         });
         tokio::spawn({
@@ -53,57 +396,118 @@ impl RenderService {
                     trace!("waiting for messages from threads");
                     tokio::select! {
                         Some(request) = requests_rx.recv() => service.handle_request(request).await,
-                        Some((speed, result)) = completed_renders_rx.recv() => {
-                            service.handle_complete_render(speed, result).await
+                        Some((key, result)) = completed_renders_rx.recv() => {
+                            service.handle_complete_render(key, result).await
                         },
+                        Some(key) = cancellations_rx.recv() => service.handle_cancellation(key),
                     }
                 }
             }
         });
-        tokio::spawn(async move {
-            info!("render task is ready");
-            // NOTE: Render requests are not handled in separate threads (yet.)
-            while let Some(speed) = renders_rx.recv().await {
-                trace!(speed, "got render request");
-                let completed_renders_tx = completed_renders_tx.clone();
-                let service = Arc::clone(&service);
-                tokio::spawn(async move {
-                    // Should be fine if we discard the error.
-                    let _ = completed_renders_tx
-                        .send((speed, service.render_speed(speed).await))
-                        .await;
-                });
+        tokio::spawn({
+            let service = Arc::clone(&service);
+            async move {
+                info!("render task is ready");
+                // Renders waiting for a free job slot, in the order they were requested.
+                let mut pending: Vec<PendingRender> = vec![];
+                loop {
+                    // NOTE: A job slot is only taken once there's a render to start, so that
+                    // which one is started can be decided as late as possible.
+                    let render_jobs = Arc::clone(&service.render_jobs);
+                    tokio::select! {
+                        render = renders_rx.recv() => match render {
+                            Some((key, span)) => {
+                                trace!(?key, "got render request");
+                                if !pending.iter().any(|&(pending, _, _)| pending == key) {
+                                    pending.push((key, Instant::now(), span));
+                                }
+                            }
+                            None => break,
+                        },
+                        permit = render_jobs.acquire_owned(), if !pending.is_empty() => {
+                            // The semaphore is never closed, so it's safe to unwrap.
+                            let permit = permit.unwrap();
+                            let next = service.next_render(&mut pending);
+                            if let Some((key, requested_at, span)) = next {
+                                let queue_wait = requested_at.elapsed();
+                                debug!(?key, ?queue_wait, "render got a job slot");
+                                service.metrics.queue_wait.observe(queue_wait);
+                                let completed_renders_tx = completed_renders_tx.clone();
+                                let job_service = Arc::clone(&service);
+                                // The render runs in the span of the request that started it.
+                                let job = tokio::spawn(
+                                    async move {
+                                        let (output_frames, format, options) = key;
+                                        let result = job_service
+                                            .render(output_frames, format, options)
+                                            .await;
+                                        drop(permit);
+                                        // Should be fine if we discard the error.
+                                        let _ = completed_renders_tx.send((key, result)).await;
+                                    }
+                                    .instrument(span),
+                                );
+                                service.jobs.insert(key, job);
+                            }
+                        },
+                    }
+                }
             }
         });
 
-        RenderServiceHandle {
+        Ok(RenderServiceHandle {
             requests: requests_tx,
-        }
+            cancellations: cancellations_tx,
+            service,
+        })
     }
 
     async fn handle_request(&self, request: QueueRequest) {
-        let QueueRequest { speed, responder } = request;
-        trace!(speed, "got queue request");
+        let QueueRequest {
+            key,
+            responder,
+            span,
+        } = request;
+        trace!(?key, "got queue request");
 
-        let mut queue = self.queues.entry(speed.to_bits()).or_default();
+        // NOTE: Requests are handled one at a time by the management task, so no other queue
+        // can be created between checking the length and inserting.
+        if !self.queues.contains_key(&key) && self.queues.len() >= self.config.max_pending {
+            debug!(
+                max_pending = self.config.max_pending,
+                "render queue is full, rejecting request"
+            );
+            let _ = responder.send(Err(Arc::new(Error::RenderQueueFull)));
+            return;
+        }
+
+        if let Scheduling::Popularity = self.config.scheduling {
+            self.popularity.lock().record(key);
+        }
+
+        let mut queue = self.queues.entry(key).or_default();
         let request_render = queue.is_empty();
         queue.push(responder);
         if request_render {
             trace!("queue is empty, sending render request");
             self.render_requests
-                .send(speed)
+                .send((key, span))
                 .await
                 .expect("render task ended");
             drop(queue);
         }
     }
 
-    async fn handle_complete_render(&self, speed: f64, result: Result<Vec<u8>, Error>) {
+    async fn handle_complete_render(&self, key: RenderKey, result: Result<Vec<u8>, Error>) {
         let result = result.map_err(Arc::new);
+        self.jobs.remove(&key);
         // This should *hopefully* lock the map for the entire duration of the function, as well
         // as holding the same lock while removing the item.
-        self.queues.remove_if_mut(&speed.to_bits(), |_, queue| {
-            for (i, waiting) in queue.drain(..).enumerate() {
+        self.queues.remove_if_mut(&key, |_, queue| {
+            // NOTE: Clients that went away are skipped, so that the positions of the ones still
+            // waiting start at 0.
+            let waiting = queue.drain(..).filter(|waiting| !waiting.is_closed());
+            for (i, waiting) in waiting.enumerate() {
                 // Ignore error if waiting channel is closed.
                 let _ = waiting.send(result.clone().map(|file| (file, i)));
             }
@@ -111,91 +515,663 @@ impl RenderService {
         });
     }
 
-    #[instrument(level = "debug", name = "render", skip(self))]
-    async fn render_speed(&self, speed: f64) -> Result<Vec<u8>, Error> {
-        // The permit must be given here because we never close the semaphore, thus it is
-        // safe to unwrap.
-        let _permit = self.render_jobs.acquire().await.unwrap();
+    /// Forgets about clients that stopped waiting for the given render. If there are none left,
+    /// the render is stopped.
+    fn handle_cancellation(&self, key: RenderKey) {
+        let abandoned = self
+            .queues
+            .remove_if_mut(&key, |_, queue| {
+                queue.retain(|waiting| !waiting.is_closed());
+                queue.is_empty()
+            })
+            .is_some();
+        if abandoned {
+            if let Some((_, job)) = self.jobs.remove(&key) {
+                debug!(
+                    ?key,
+                    "nobody is waiting for the render anymore, stopping it"
+                );
+                // NOTE: Aborting the job drops the encoder process, which kills it.
+                job.abort();
+            }
+        }
+    }
 
-        debug!("starting render");
+    /// Takes the render that should be started next out of the pending ones. Renders that nobody
+    /// is waiting for anymore are dropped.
+    fn next_render(&self, pending: &mut Vec<PendingRender>) -> Option<PendingRender> {
+        pending.retain(|(key, _, _)| self.queues.contains_key(key));
+        let index = match self.config.scheduling {
+            Scheduling::Fifo => 0,
+            Scheduling::Popularity => {
+                let popularity = self.popularity.lock();
+                // NOTE: Ties go to the render that was requested first.
+                pending
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|&(i, (key, _, _))| (popularity.get(key), Reverse(i)))
+                    .map_or(0, |(i, _)| i)
+            }
+        };
+        (index < pending.len()).then(|| pending.remove(index))
+    }
 
-        let output_frames = (self.animation_info.frame_count as f64 / speed).floor() as usize;
-        if output_frames <= 1 {
-            debug!("requested speed is too fast");
-            return Err(Error::SpeedTooFast);
-        }
-        if output_frames > self.animation_info.frame_count {
-            debug!("requested speed is too slow");
-            return Err(Error::SpeedTooSlow);
-        }
+    /// Renders the animation. The caller must be holding a job slot.
+    #[instrument(level = "debug", name = "render", skip(self))]
+    async fn render(
+        &self,
+        output_frames: usize,
+        format: Format,
+        options: RenderOptions,
+    ) -> Result<Vec<u8>, Error> {
+        debug!("starting render");
+        let started_at = Instant::now();
 
-        let args = {
+        let stream = self
+            .config
+            .stream
+            .then(|| self.stream((output_frames, format, options)));
+        let encoder = self.config.encoder_for(format);
+        let output_file = encoder
+            .writes_to_file()
+            .then(|| OutputFile::new(self.config.output_dir.as_deref(), format));
+        let (args, frames) = {
             let mut args = vec![];
-            for flag in &self.config.encoder_flags {
+            let mut frames: Vec<OsString> = vec![];
+            for flag in &encoder.encoder_flags {
+                // The frames go wherever the encoder wants them, but everything else is always
+                // passed as an argument.
+                let frame_list = if encoder.frames_on_stdin {
+                    &mut frames
+                } else {
+                    &mut args
+                };
                 if flag.contains("{frame_indices}") {
-                    let mut accumulator: f64 = 0.0;
-                    args.extend((0..output_frames).map(|_| {
-                        let input_frame = accumulator.floor() as usize + 1;
-                        accumulator += speed;
+                    frame_list.extend((0..output_frames).map(|output_frame| {
+                        let input_frame = self.input_frame(output_frame, output_frames);
                         flag.replace("{frame_indices}", &input_frame.to_string())
                             .into()
                     }));
+                } else if flag.contains("{input_filenames}") {
+                    frame_list.extend((0..output_frames).map(|output_frame| {
+                        let input_frame = self.input_frame(output_frame, output_frames);
+                        let path = self
+                            .config
+                            .frame_path(input_frame)
+                            .expect("frames_dir is checked on startup");
+                        flag.replace("{input_filenames}", &path.to_string_lossy())
+                            .into()
+                    }));
+                } else if let (true, Some(output_file)) = (flag.contains("{output}"), &output_file)
+                {
+                    let path = output_file.path.to_string_lossy();
+                    args.push(flag.replace("{output}", &path).into())
                 } else if flag.contains("{fps}") {
                     args.push(OsString::from(self.animation_info.fps.to_string()))
+                } else if flag.contains("{format}") {
+                    args.push(flag.replace("{format}", format.name()).into())
+                } else if OPTION_PLACEHOLDERS
+                    .iter()
+                    .any(|placeholder| flag.contains(placeholder))
+                {
+                    args.extend(substitute_options(flag, options).map(OsString::from));
                 } else {
                     args.push(OsString::from(flag));
                 }
             }
-            args
+            (args, frames)
         };
-        trace!(
-            ?self.config.encoder,
-            ?args,
-            "starting render job",
-        );
-        let output = Command::new(&self.config.encoder)
-            .stdout(Stdio::piped())
-            .args(&args)
+        let stdin = encoder.frames_on_stdin.then(|| {
+            let mut stdin = vec![];
+            for frame in &frames {
+                stdin.extend_from_slice(frame.to_string_lossy().as_bytes());
+                stdin.extend_from_slice(encoder.frame_separator.as_bytes());
+            }
+            stdin
+        });
+        // NOTE: Only failures of the encoder itself are retried, since they may be caused by
+        // transient conditions like running out of memory or disk space. Anything else would
+        // fail the same way again. Neither are renders that were already partially sent to
+        // clients.
+        let mut attempt = 0;
+        let result = loop {
+            let streamed = stream.as_ref().is_some_and(|stream| !stream.is_empty());
+            let output_path = output_file.as_ref().map(|file| file.path.as_path());
+            match self
+                .run_encoder(
+                    &encoder.encoder,
+                    &args,
+                    stdin.as_deref(),
+                    output_path,
+                    stream.as_deref(),
+                )
+                .await
+            {
+                Err(Error::EncoderExitCode | Error::EncoderKilled(_))
+                    if attempt < self.config.retries && !streamed =>
+                {
+                    attempt += 1;
+                    let backoff = Duration::from_millis(500) * 2u32.saturating_pow(attempt - 1);
+                    warn!(attempt, ?backoff, "encoder failed, retrying");
+                    tokio::time::sleep(backoff).await;
+                }
+                result => break result,
+            }
+        };
+        if let Some(stream) = &stream {
+            stream.finish();
+        }
+        let output = result?;
+
+        let encode_time = started_at.elapsed();
+        self.metrics.encode.observe(encode_time);
+        debug!(?encode_time, "render complete");
+
+        Ok(output)
+    }
+
+    #[instrument(level = "debug", name = "preview", skip(self))]
+    async fn render_preview(&self, output_frames: usize) -> Result<Vec<u8>, Error> {
+        let flags = self
+            .config
+            .preview_flags
+            .as_ref()
+            .ok_or(Error::PreviewUnavailable)?;
+        let _permit = self.render_jobs.acquire().await.unwrap();
+
+        // The frame in the middle of the animation is shown, as the first one usually isn't very
+        // interesting.
+        let input_frame = self.input_frame(output_frames / 2, output_frames);
+        let args: Vec<_> = flags
+            .iter()
+            .map(|flag| OsString::from(flag.replace("{frame_index}", &input_frame.to_string())))
+            .collect();
+        self.run_encoder(
+            &self.config.default_encoder.encoder,
+            &args,
+            None,
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Returns the stream for the render with the given key, starting a new one if there isn't
+    /// one in progress.
+    fn stream(&self, key: RenderKey) -> Arc<RenderStream> {
+        let mut entry = self.streams.entry(key).or_default();
+        match entry.upgrade() {
+            Some(stream) if !stream.is_finished() => stream,
+            _ => {
+                let stream = Arc::new(RenderStream::default());
+                *entry = Arc::downgrade(&stream);
+                stream
+            }
+        }
+    }
+
+    /// Returns the number of frames the animation has when played at the given speed.
+    fn output_frame_count(&self, speed: f64) -> Result<usize, Error> {
+        // NOTE: Quantized speeds divide the frame count evenly, but floating point error can put
+        // the quotient just below the integer it should be, hence the bit of leeway.
+        let output_frames =
+            (self.animation_info.frame_count as f64 / speed + 1e-6).floor() as usize;
+        if output_frames <= 1 {
+            debug!("requested speed is too fast");
+            return Err(Error::SpeedTooFast);
+        }
+        if output_frames > self.animation_info.max_output_frames {
+            debug!("requested speed is too slow");
+            return Err(Error::SpeedTooSlow);
+        }
+        Ok(output_frames)
+    }
+
+    /// Returns the 1-based index of the input frame shown as the given output frame. The output
+    /// frames are spread evenly across the whole animation and wrap around at its end, so that
+    /// the step from the last output frame back to the first is the same as between any other
+    /// two, and the animation loops without a hitch.
+    fn input_frame(&self, output_frame: usize, output_frames: usize) -> usize {
+        let frame_count = self.animation_info.frame_count;
+        // NOTE: This is `round(output_frame * frame_count / output_frames)` in integer
+        // arithmetic, so that no error accumulates over long animations.
+        let rounded = (output_frame * frame_count + output_frames / 2) / output_frames;
+        rounded % frame_count + 1
+    }
+
+    /// Runs an encoder with the given arguments, returning what it wrote to stdout, or to
+    /// `output_path` if one is given. Output written to stdout is also written to the stream as it
+    /// comes in, if one is given.
+    async fn run_encoder(
+        &self,
+        encoder: &Path,
+        args: &[OsString],
+        stdin: Option<&[u8]>,
+        output_path: Option<&Path>,
+        stream: Option<&RenderStream>,
+    ) -> Result<Vec<u8>, Error> {
+        trace!(?encoder, ?args, "starting render job");
+        let mut command = Command::new(encoder);
+        if stdin.is_some() {
+            command.stdin(Stdio::piped());
+        }
+        // NOTE: Encoders writing to a file are only expected to log to stdout, if anything.
+        let stdout = if output_path.is_some() {
+            Stdio::null()
+        } else {
+            Stdio::piped()
+        };
+        let mut child = command
+            .stdout(stdout)
+            .args(args)
+            // NOTE: When the job times out, the child is dropped along with the future waiting
+            // for it, which kills it.
+            .kill_on_drop(true)
             .spawn()
-            .map_err(Error::Encoder)?
-            .wait_with_output()
+            .map_err(Error::Encoder)?;
+        let child_stdout = child.stdout.take();
+        let child_stdin = child.stdin.take();
+        let feed = async {
+            if let (Some(mut child_stdin), Some(stdin)) = (child_stdin, stdin) {
+                // NOTE: The encoder is free to stop reading early, eg. when it fails; in that case
+                // its exit code tells what went wrong.
+                match child_stdin.write_all(stdin).await {
+                    Err(error) if error.kind() != ErrorKind::BrokenPipe => return Err(error),
+                    _ => (),
+                }
+                // Dropping stdin closes it, so that the encoder knows there are no more frames.
+            }
+            Ok(())
+        };
+        let read = async {
+            let mut output = vec![];
+            let mut stdout = match child_stdout {
+                Some(stdout) => stdout,
+                None => return Ok(output),
+            };
+            let mut chunk = vec![0; 16 * 1024];
+            loop {
+                let count = stdout.read(&mut chunk).await?;
+                if count == 0 {
+                    break;
+                }
+                output.extend_from_slice(&chunk[..count]);
+                if let Some(stream) = stream {
+                    stream.write(&chunk[..count]);
+                }
+            }
+            Ok::<_, std::io::Error>(output)
+        };
+        let encode = async {
+            let (fed, output) = tokio::join!(feed, read);
+            fed?;
+            let output = output?;
+            Ok::<_, std::io::Error>((child.wait().await?, output))
+        };
+        let timeout = Duration::from_secs(self.config.timeout);
+        let (status, mut output) = tokio::time::timeout(timeout, encode)
             .await
+            .map_err(|_| {
+                error!(?timeout, "encoder timed out and was killed");
+                Error::EncoderTimeout
+            })?
             .map_err(Error::Encoder)?;
 
-        if !output.status.success() {
-            error!(exit_code = ?output.status, "encoder finished with a non-zero exit code");
+        if let Some(signal) = termination_signal(status) {
+            error!(signal, "encoder was killed by a signal");
+            return Err(Error::EncoderKilled(signal));
+        }
+        if !status.success() {
+            error!(exit_code = ?status, "encoder finished with a non-zero exit code");
             return Err(Error::EncoderExitCode);
         }
+        if let Some(output_path) = output_path {
+            output = tokio::fs::read(output_path).await.map_err(Error::Encoder)?;
+        }
+        // An encoder that exits successfully without writing anything is broken; caching its
+        // output would serve an empty file for this speed until it's purged.
+        if output.is_empty() {
+            error!("encoder finished successfully but did not output anything");
+            return Err(Error::EncoderNoOutput);
+        }
+
+        Ok(output)
+    }
+}
 
-        debug!("render complete");
+/// Returns the signal the process was terminated by, if it didn't exit on its own.
+#[cfg(unix)]
+fn termination_signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn termination_signal(_status: ExitStatus) -> Option<i32> {
+    None
+}
 
-        Ok(output.stdout)
+/// Returns whether the encoder at the given path can be run. Bare names are looked up in `PATH`,
+/// the same way as when the encoder is started.
+fn is_executable(path: &Path) -> bool {
+    if path.components().count() > 1 {
+        return is_executable_file(path);
     }
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|dir| is_executable_file(&dir.join(path)))
+    })
+}
+
+#[cfg(unix)]
+fn is_executable_file(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable_file(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Placeholders that are replaced with the options requested by the client.
+const OPTION_PLACEHOLDERS: [&str; 4] = ["{width}", "{height}", "{quality}", "{dither}"];
+
+/// Substitutes the requested options into a flag, which may contain more than one of them (eg.
+/// `--resize={width}x{height}`.) Returns `None` if the flag refers to an option that the client
+/// didn't request.
+fn substitute_options(flag: &str, options: RenderOptions) -> Option<String> {
+    let values = [
+        options.width.map(|width| width.to_string()),
+        options.height.map(|height| height.to_string()),
+        options.quality.map(|quality| quality.to_string()),
+        options.dither.map(|dither| dither.to_string()),
+    ];
+    let mut flag = flag.to_owned();
+    for (placeholder, value) in OPTION_PLACEHOLDERS.into_iter().zip(values) {
+        if flag.contains(placeholder) {
+            flag = flag.replace(placeholder, &value?);
+        }
+    }
+    Some(flag)
 }
 
 type RenderResult = Result<(Vec<u8>, usize), Arc<Error>>;
 
+/// The output of a render, readable while the encoder is still writing it.
+#[derive(Default)]
+pub struct RenderStream {
+    data: Mutex<Vec<u8>>,
+    /// Notified whenever more data is written.
+    written: Notify,
+    finished: AtomicBool,
+}
+
+impl RenderStream {
+    fn write(&self, chunk: &[u8]) {
+        self.data.lock().extend_from_slice(chunk);
+        self.written.notify_waiters();
+    }
+
+    fn finish(&self) {
+        self.finished.store(true, Ordering::Release);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.lock().is_empty()
+    }
+
+    /// Returns everything written past the given offset so far.
+    pub fn read_from(&self, offset: usize) -> Vec<u8> {
+        self.data
+            .lock()
+            .get(offset..)
+            .map(<[u8]>::to_vec)
+            .unwrap_or_default()
+    }
+
+    /// Waits until there's data past the given offset.
+    pub async fn wait_past(&self, offset: usize) {
+        loop {
+            // NOTE: The notification must be created before checking the length, otherwise a
+            // write in between the two would be missed.
+            let written = self.written.notified();
+            if self.data.lock().len() > offset {
+                return;
+            }
+            written.await;
+        }
+    }
+}
+
 struct QueueRequest {
-    speed: f64,
+    key: RenderKey,
     responder: oneshot::Sender<RenderResult>,
+    /// The span of the request. If it starts a new render, the render runs in it.
+    span: Span,
 }
 
+/// Lets the render service know if a client stops waiting for a render before it's done.
+struct CancelOnDrop<'a> {
+    key: RenderKey,
+    cancellations: &'a mpsc::UnboundedSender<RenderKey>,
+    done: bool,
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.cancellations.send(self.key);
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct RenderServiceHandle {
     requests: mpsc::Sender<QueueRequest>,
+    cancellations: mpsc::UnboundedSender<RenderKey>,
+    service: Arc<RenderService>,
 }
 
 impl RenderServiceHandle {
-    /// On success, returns the encoded GIF file and the requester's position in the queue.
-    pub async fn render_speed(&self, speed: f64) -> RenderResult {
+    /// Returns whether the render service is still accepting requests.
+    pub fn is_running(&self) -> bool {
+        !self.requests.is_closed()
+    }
+
+    /// Returns the paths to the encoder executables that cannot be run (anymore). Bare names are
+    /// looked up in `PATH`, like when an encoder is started.
+    pub fn unavailable_encoders(&self) -> impl Iterator<Item = &Path> {
+        let config = &self.service.config;
+        let format_encoders = config.encoders.values().map(|config| &*config.encoder);
+        std::iter::once(&*config.default_encoder.encoder)
+            .chain(format_encoders)
+            .filter(|encoder| !is_executable(encoder))
+    }
+
+    /// Checks that the animation's frames can still be read, without rendering anything. Only
+    /// frames the server knows the paths of are checked, ie. the frames directory and its first
+    /// frame; files that only appear in the encoder's flags are up to the encoder.
+    pub async fn check_frames_accessible(&self) -> Result<(), String> {
+        let config = &self.service.config;
+        if let Some(frames_dir) = &config.frames_dir {
+            if let Err(error) = tokio::fs::read_dir(frames_dir).await {
+                return Err(format!(
+                    "frames directory ({}) is not accessible: {error}",
+                    frames_dir.display()
+                ));
+            }
+        }
+        if let Some(first_frame) = config.frame_path(1).filter(|_| config.uses_filenames()) {
+            match tokio::fs::metadata(&first_frame).await {
+                Ok(metadata) if metadata.is_file() => (),
+                Ok(_) => {
+                    return Err(format!(
+                        "the first frame ({}) is not a file",
+                        first_frame.display()
+                    ))
+                }
+                Err(error) => {
+                    return Err(format!(
+                        "the first frame ({}) is not accessible: {error}",
+                        first_frame.display()
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of distinct renders that are currently queued or in progress.
+    pub fn queue_depth(&self) -> usize {
+        self.service.queues.len()
+    }
+
+    /// Returns the maximum number of distinct renders that can be queued or in progress.
+    pub fn queue_capacity(&self) -> usize {
+        self.service.config.max_pending
+    }
+
+    /// Returns the number of clients waiting for the given render, or `None` if it's not queued.
+    pub fn waiting_clients(
+        &self,
+        output_frames: usize,
+        format: Format,
+        options: RenderOptions,
+    ) -> Option<usize> {
+        self.service
+            .queues
+            .get(&(output_frames, format, options))
+            .map(|queue| queue.len())
+    }
+
+    /// Returns whether renders are sent to clients while they're still being encoded.
+    pub fn streams_output(&self) -> bool {
+        self.service.config.stream
+    }
+
+    /// Returns the stream of the given render. If the render hasn't started yet, the stream is
+    /// created ahead of time, and it's picked up by the render once it does. The stream is only
+    /// written to while the render is in progress, so it must be read alongside a request for
+    /// the render.
+    pub fn subscribe(
+        &self,
+        output_frames: usize,
+        format: Format,
+        options: RenderOptions,
+    ) -> Arc<RenderStream> {
+        self.service
+            .streams
+            .retain(|_, stream| stream.strong_count() > 0);
+        self.service.stream((output_frames, format, options))
+    }
+
+    /// Returns the number of frames the animation has when played at the given speed. Renders
+    /// are identified by this rather than the speed, as it's what decides which frames end up in
+    /// the animation.
+    pub fn output_frame_count(&self, speed: f64) -> Result<usize, Error> {
+        self.service.output_frame_count(speed)
+    }
+
+    /// Extracts the middle frame of the animation with the given number of frames as a PNG.
+    /// Previews skip the render queue, as they're much cheaper to produce than whole animations.
+    pub async fn render_preview(&self, output_frames: usize) -> Result<Vec<u8>, Error> {
+        self.service.render_preview(output_frames).await
+    }
+
+    /// On success, returns the encoded file and the requester's position in the queue. If the
+    /// returned future is dropped before completion and nobody else is waiting for the same
+    /// render, the render is stopped.
+    pub async fn render(
+        &self,
+        output_frames: usize,
+        format: Format,
+        options: RenderOptions,
+    ) -> RenderResult {
+        let key = (output_frames, format, options);
         let (tx, rx) = oneshot::channel();
         self.requests
             .send(QueueRequest {
-                speed,
+                key,
                 responder: tx,
+                span: Span::current(),
             })
             .await
             .map_err(|_| Error::EncodingJobExited)
             .expect("render service quit unexpectedly");
-        rx.await.map_err(|_| Error::EncodingJobExited)?
+        let mut guard = CancelOnDrop {
+            key,
+            cancellations: &self.cancellations,
+            done: false,
+        };
+        let result = rx.await;
+        guard.done = true;
+        result.map_err(|_| Error::EncodingJobExited)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, path::Path, sync::Arc};
+
+    use crate::{animation_info::AnimationInfo, metrics::Metrics};
+
+    use super::{RenderService, RenderServiceHandle};
+
+    /// Starts a render service for two frames in `frames_dir`, encoded by `encoder`.
+    fn spawn(encoder: &str, frames_dir: &Path) -> RenderServiceHandle {
+        let config = toml::from_str(&format!(
+            r#"
+                encoder = "{encoder}"
+                encoder_flags = ["{{input_filenames}}"]
+                frames_dir = "{}"
+                max_jobs = 1
+            "#,
+            frames_dir.display()
+        ))
+        .unwrap();
+        let info = AnimationInfo {
+            fps: 50.0,
+            wave_count: 1.0,
+            frame_count: 2,
+            max_output_frames: 2,
+            version: None,
+        };
+        RenderService::spawn(config, info, Arc::new(Metrics::default())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn health_checks() {
+        let dir = std::env::temp_dir().join(format!("smugdancer-frames-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1.png"), b"").unwrap();
+        std::fs::write(dir.join("2.png"), b"").unwrap();
+
+        // NOTE: Bare names are found through PATH, like when the encoder is run.
+        let render_service = spawn("sh", &dir);
+        assert_eq!(render_service.unavailable_encoders().count(), 0);
+        assert!(render_service.check_frames_accessible().await.is_ok());
+
+        let encoder = dir.join("encoder.sh");
+        std::fs::write(&encoder, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&encoder, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let script_service = spawn(encoder.to_str().unwrap(), &dir);
+        assert_eq!(script_service.unavailable_encoders().count(), 0);
+        std::fs::remove_file(&encoder).unwrap();
+        assert_eq!(
+            script_service.unavailable_encoders().collect::<Vec<_>>(),
+            [encoder.as_path()]
+        );
+
+        std::fs::remove_file(dir.join("1.png")).unwrap();
+        assert!(render_service.check_frames_accessible().await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let problem = render_service.check_frames_accessible().await.unwrap_err();
+        assert!(problem.starts_with("frames directory"), "{problem}");
     }
 }